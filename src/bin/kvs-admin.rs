@@ -0,0 +1,379 @@
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use clap::{Parser, Subcommand};
+use log::{error, info};
+
+use kvs::{
+    Damage, KvError, KvStore, KvStoreOptions, KvsEngine, Result, SledKvsEngine, SyncPolicy,
+    VerifyReport, WriteBatch,
+};
+
+/// Number of pairs copied per write batch during a migration.
+const MIGRATE_BATCH_SIZE: usize = 1000;
+
+#[derive(Parser)]
+#[command(
+    name = "kvs-admin",
+    version,
+    about = "Offline tools for kvs data directories"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+
+    /// File holding the base64 keys of an encrypted kvs store, the current one first
+    /// (defaults to $KVS_ENCRYPTION_KEY)
+    #[arg(long, global = true, value_name = "PATH")]
+    encryption_key_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Copy a stopped store to another engine and swap it into place
+    ///
+    /// The previous data is kept next to the directory with an `.old`
+    /// suffix. Keys with a TTL keep their expiry time. Rerunning a
+    /// migration that was interrupted while swapping the directories
+    /// finishes the swap.
+    Migrate {
+        /// Engine the directory uses now
+        #[arg(long, value_name = "ENGINE-NAME", value_parser = ["kvs", "sled"])]
+        from: String,
+        /// Engine to migrate to
+        #[arg(long, value_name = "ENGINE-NAME", value_parser = ["kvs", "sled"])]
+        to: String,
+        /// Data directory of the store
+        dir: PathBuf,
+    },
+    /// Check every log of a stopped kvs store and report damage
+    ///
+    /// Exits with a nonzero code if a log is corrupt, unless `--repair`
+    /// is given.
+    Verify {
+        /// Data directory of the store
+        dir: PathBuf,
+        /// Cut damaged logs back to their last intact record
+        #[arg(long)]
+        repair: bool,
+        /// Also cut damaged generations other than the newest, reverting
+        /// the keys they wrote to older values
+        #[arg(long, requires = "repair")]
+        force: bool,
+    },
+}
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .target(env_logger::Target::Stderr)
+        .init();
+
+    let cli = Cli::parse();
+
+    let result = KvStoreOptions::new()
+        .sync_policy(SyncPolicy::Always)
+        .with_encryption_keys_from(cli.encryption_key_file.as_deref())
+        .and_then(|options| match cli.command {
+            Commands::Migrate { from, to, dir } => migrate(&from, &to, &dir, &options),
+            Commands::Verify { dir, repair, force } => verify(&dir, repair, force, &options),
+        });
+    if let Err(e) = result {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+/// Migrates the store in `dir` from engine `from` to engine `to`, opening
+/// the kvs side with `options`.
+///
+/// If an earlier run was interrupted between swapping the directories, the
+/// swap is finished instead.
+fn migrate(from: &str, to: &str, dir: &Path, options: &KvStoreOptions) -> Result<()> {
+    if from == to {
+        return Err(KvError::StringError(format!(
+            "The directory already uses the '{}' engine",
+            to
+        )));
+    }
+    if !dir.exists() {
+        return finish_swap(dir);
+    }
+    let dir = fs::canonicalize(dir)?;
+    if let Ok(prev) = fs::read_to_string(dir.join("engine")) {
+        if prev != from {
+            return Err(KvError::StringError(format!(
+                "Wrong engine! The directory uses '{}', not '{}'.",
+                prev, from
+            )));
+        }
+    }
+    let staging = sibling(&dir, ".migrating")?;
+    let old = sibling(&dir, ".old")?;
+    for path in [&staging, &old] {
+        if path.exists() {
+            return Err(KvError::StringError(format!(
+                "{} is in the way; remove it first",
+                path.display()
+            )));
+        }
+    }
+
+    info!("Migrating {} from {} to {}", dir.display(), from, to);
+    fs::create_dir(&staging)?;
+    // Every write is synced, so that the copy is on disk before the
+    // directories are swapped.
+    let open_kvs = |path: &Path| KvStore::open_with(path, options.clone());
+    let count = match from {
+        "kvs" => copy_and_verify(open_kvs(&dir)?, &staging, open_sled)?,
+        _ => copy_and_verify(open_sled(&dir)?, &staging, open_kvs)?,
+    };
+    fs::write(staging.join("engine"), to)?;
+    File::open(staging.join("engine"))?.sync_all()?;
+    File::open(&staging)?.sync_all()?;
+
+    // Between the renames the store is only reachable under the sibling
+    // names; a crash there is finished by the next run (see
+    // `finish_swap`). Each rename is made durable before the next.
+    fs::rename(&dir, &old)?;
+    sync_parent(&dir)?;
+    fs::rename(&staging, &dir)?;
+    sync_parent(&dir)?;
+    info!(
+        "Migrated {} keys; previous data kept in {}",
+        count,
+        old.display()
+    );
+    Ok(())
+}
+
+/// Verifies the kvs store in `dir`, repairing it if asked to.
+fn verify(dir: &Path, repair: bool, force: bool, options: &KvStoreOptions) -> Result<()> {
+    match fs::read_to_string(dir.join("engine")) {
+        Ok(engine) if engine != "kvs" => {
+            return Err(KvError::StringError(format!(
+                "Wrong engine! The directory uses '{}', not 'kvs'.",
+                engine
+            )))
+        }
+        _ => {}
+    }
+    let report = if repair {
+        KvStore::repair_with(dir, options, force)?
+    } else {
+        KvStore::verify_with(dir, options)?
+    };
+    print_report(&report)?;
+    if report.is_corrupt() && !repair {
+        return Err(KvError::StringError(
+            "The store is corrupt; run with --repair to cut damaged logs".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Prints `report` to stdout, one finding per line.
+fn print_report(report: &VerifyReport) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for g in &report.generations {
+        write!(
+            stdout,
+            "{}.log: {} bytes, {} live, {} stale",
+            g.gen,
+            g.bytes,
+            g.live_bytes,
+            g.stale_bytes()
+        )?;
+        match g.damage {
+            Some(Damage::Corrupt { pos }) => writeln!(stdout, ", corrupt record at {}", pos)?,
+            Some(Damage::Truncated { pos }) => writeln!(stdout, ", truncated at {}", pos)?,
+            None => writeln!(stdout)?,
+        }
+    }
+    for gen in &report.duplicate_gens {
+        writeln!(stdout, "duplicate generation: {}", gen)?;
+    }
+    for path in &report.orphaned_files {
+        writeln!(stdout, "orphaned file: {}", path.display())?;
+    }
+    for path in &report.stray_files {
+        writeln!(stdout, "stray file: {}", path.display())?;
+    }
+    writeln!(
+        stdout,
+        "total: {} bytes, {} live, {} stale",
+        report.bytes(),
+        report.live_bytes(),
+        report.bytes() - report.live_bytes()
+    )
+}
+
+/// Copies every live pair of `source`, with its expiry time, into a new
+/// store opened by `open` in `staging`, then reopens that store and checks
+/// it against `source`. Returns the number of pairs.
+fn copy_and_verify<S: KvsEngine, T: KvsEngine>(
+    source: S,
+    staging: &Path,
+    open: impl Fn(&Path) -> Result<T>,
+) -> Result<u64> {
+    let target = open(staging)?;
+    let mut count = 0;
+    let mut batch = WriteBatch::new();
+    for pair in source.export_pairs()? {
+        let (key, value) = pair?;
+        match source.expiry_bytes(key.clone())? {
+            Some(expires_at) => batch.set_expiring_bytes(key, value, expires_at),
+            None => batch.set_bytes(key, value),
+        };
+        count += 1;
+        if batch.len() == MIGRATE_BATCH_SIZE {
+            target.write_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        target.write_batch(batch)?;
+    }
+    drop(target);
+
+    verify_copy(&source, &open(staging)?)?;
+    Ok(count)
+}
+
+/// Checks that `target` holds the same live pairs as `source`, with the
+/// same expiry times.
+///
+/// The two stores are scanned side by side in key order. They are not read
+/// at the same instant, so a key found in only one of them is accepted if
+/// it has expired since.
+fn verify_copy<S: KvsEngine, T: KvsEngine>(source: &S, target: &T) -> Result<()> {
+    let mut source_pairs = source.export_pairs()?;
+    let mut target_pairs = target.export_pairs()?;
+    let mut next_source = source_pairs.next().transpose()?;
+    let mut next_target = target_pairs.next().transpose()?;
+    loop {
+        match (&next_source, &next_target) {
+            (None, None) => return Ok(()),
+            (Some((key, value)), Some((target_key, target_value))) if key == target_key => {
+                if value != target_value
+                    || source.expiry_bytes(key.clone())? != target.expiry_bytes(key.clone())?
+                {
+                    return Err(verify_error(key, "differs in the copy"));
+                }
+                next_source = source_pairs.next().transpose()?;
+                next_target = target_pairs.next().transpose()?;
+            }
+            (Some((key, _)), next) if next.as_ref().is_none_or(|(other, _)| key < other) => {
+                if !has_expired(source.expiry_bytes(key.clone())?) {
+                    return Err(verify_error(key, "is missing from the copy"));
+                }
+                next_source = source_pairs.next().transpose()?;
+            }
+            (_, Some((key, _))) => {
+                if !has_expired(target.expiry_bytes(key.clone())?) {
+                    return Err(verify_error(key, "is not in the source"));
+                }
+                next_target = target_pairs.next().transpose()?;
+            }
+            (Some(_), None) => unreachable!("handled by the arm above"),
+        }
+    }
+}
+
+/// Whether a key with expiry time `expires_at` has expired.
+fn has_expired(expires_at: Option<SystemTime>) -> bool {
+    expires_at.is_some_and(|time| time <= SystemTime::now())
+}
+
+/// Builds the error reporting that verifying `key` failed.
+fn verify_error(key: &[u8], problem: &str) -> KvError {
+    KvError::StringError(format!(
+        "Verification failed: key {:?} {}",
+        String::from_utf8_lossy(key),
+        problem
+    ))
+}
+
+/// Opens the `SledKvsEngine` in `path`.
+///
+/// sled releases its file lock from a background thread after the last
+/// handle to a database drops, so reopening one right away may find it
+/// still locked for a moment. sled reports that only through the message
+/// of an `ErrorKind::Other` error.
+fn open_sled(path: &Path) -> Result<SledKvsEngine> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e))
+                if e.to_string().starts_with("could not acquire lock")
+                    && Instant::now() < deadline =>
+            {
+                thread::sleep(Duration::from_millis(10));
+            }
+            result => return Ok(SledKvsEngine::new(result?)),
+        }
+    }
+}
+
+/// Completes a migration of `dir` that stopped between renaming `dir` to
+/// its `.old` sibling and the `.migrating` copy to `dir`.
+///
+/// The copy is complete and synced before the first rename, so it only
+/// needs to be moved into place.
+fn finish_swap(dir: &Path) -> Result<()> {
+    let parent = match dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Some(name) = dir.file_name() else {
+        return Err(KvError::StringError(format!(
+            "{} is not a data directory",
+            dir.display()
+        )));
+    };
+    let dir = fs::canonicalize(parent)?.join(name);
+    let staging = sibling(&dir, ".migrating")?;
+    let old = sibling(&dir, ".old")?;
+    if !(staging.is_dir() && old.is_dir()) {
+        return Err(KvError::StringError(format!(
+            "{} does not exist",
+            dir.display()
+        )));
+    }
+    let engine = fs::read_to_string(staging.join("engine"))?;
+    fs::rename(&staging, &dir)?;
+    sync_parent(&dir)?;
+    info!(
+        "Finished an interrupted migration of {} to {}; previous data kept in {}",
+        dir.display(),
+        engine,
+        old.display()
+    );
+    Ok(())
+}
+
+/// Syncs the directory holding `path`, making a rename of `path` durable.
+fn sync_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Returns the path next to `dir` whose name is that of `dir` followed by
+/// `suffix`.
+fn sibling(dir: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = dir.file_name().map(OsStr::to_os_string);
+    let Some(mut name) = name else {
+        return Err(KvError::StringError(format!(
+            "{} is not a data directory",
+            dir.display()
+        )));
+    };
+    name.push(suffix);
+    Ok(dir.with_file_name(name))
+}
@@ -1,9 +1,13 @@
+use std::fs::File;
+use std::io::{self, BufReader, Write};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 
 use clap::{Parser, Subcommand};
 
-use kvs::KvsClient;
+use kvs::{Event, KvsClient};
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4000";
 
@@ -22,6 +26,9 @@ enum Commands {
         key: String,
         /// The value
         value: String,
+        /// Expire the key after this many seconds
+        #[arg(long, value_name = "SECONDS")]
+        ttl: Option<u64>,
         /// Server address
         #[arg(long, default_value = DEFAULT_ADDR, value_name = "IP-PORT")]
         addr: SocketAddr,
@@ -42,18 +49,64 @@ enum Commands {
         #[arg(long, default_value = DEFAULT_ADDR, value_name = "IP-PORT")]
         addr: SocketAddr,
     },
+    /// Set a key to a new value only if it holds the expected value
+    Cas {
+        /// The key
+        key: String,
+        /// The value the key must hold (the key must be absent if omitted)
+        #[arg(long)]
+        expected: Option<String>,
+        /// The value to store (the key is removed if omitted)
+        #[arg(long)]
+        new: Option<String>,
+        /// Server address
+        #[arg(long, default_value = DEFAULT_ADDR, value_name = "IP-PORT")]
+        addr: SocketAddr,
+    },
+    /// Write every pair to stdout, one JSON object per line
+    Export {
+        /// Server address
+        #[arg(long, default_value = DEFAULT_ADDR, value_name = "IP-PORT")]
+        addr: SocketAddr,
+    },
+    /// Set every pair of a dump written by `export`
+    Import {
+        /// The dump to read (stdin if omitted)
+        file: Option<PathBuf>,
+        /// Server address
+        #[arg(long, default_value = DEFAULT_ADDR, value_name = "IP-PORT")]
+        addr: SocketAddr,
+    },
+    /// Print writes to keys starting with a prefix as they happen
+    Watch {
+        /// The key prefix (every key if empty)
+        #[arg(default_value = "")]
+        prefix: String,
+        /// Server address
+        #[arg(long, default_value = DEFAULT_ADDR, value_name = "IP-PORT")]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Set { key, value, addr } => {
+        Commands::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
             let mut client = KvsClient::connect(addr).unwrap_or_else(|e| {
                 eprintln!("Failed to connect to server: {}", e);
                 exit(1);
             });
-            if let Err(e) = client.set(key, value) {
+            let result = match ttl {
+                Some(secs) => client.set_with_ttl(key, value, Duration::from_secs(secs)),
+                None => client.set(key, value),
+            };
+            if let Err(e) = result {
                 eprintln!("{}", e);
                 exit(1);
             }
@@ -63,8 +116,15 @@ fn main() {
                 eprintln!("Failed to connect to server: {}", e);
                 exit(1);
             });
-            match client.get(key) {
-                Ok(Some(value)) => println!("{}", value),
+            match client.get_bytes(key.into_bytes()) {
+                Ok(Some(value)) => {
+                    // Values may be binary, so write them out unmodified.
+                    let mut stdout = io::stdout().lock();
+                    if let Err(e) = stdout.write_all(&value).and_then(|()| writeln!(stdout)) {
+                        eprintln!("{}", e);
+                        exit(1);
+                    }
+                }
                 Ok(None) => println!("Key not found"),
                 Err(e) => {
                     eprintln!("{}", e);
@@ -82,5 +142,90 @@ fn main() {
                 exit(1);
             }
         }
+        Commands::Cas {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            let mut client = KvsClient::connect(addr).unwrap_or_else(|e| {
+                eprintln!("Failed to connect to server: {}", e);
+                exit(1);
+            });
+            match client.compare_and_swap(key, expected, new) {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("Value mismatch");
+                    exit(1);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Export { addr } => {
+            let mut client = KvsClient::connect(addr).unwrap_or_else(|e| {
+                eprintln!("Failed to connect to server: {}", e);
+                exit(1);
+            });
+            if let Err(e) = client.export(io::stdout().lock()) {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Commands::Import { file, addr } => {
+            let mut client = KvsClient::connect(addr).unwrap_or_else(|e| {
+                eprintln!("Failed to connect to server: {}", e);
+                exit(1);
+            });
+            let result = match file {
+                Some(path) => File::open(path)
+                    .map_err(Into::into)
+                    .and_then(|file| client.import(BufReader::new(file))),
+                None => client.import(io::stdin().lock()),
+            };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+        Commands::Watch { prefix, addr } => {
+            let client = KvsClient::connect(addr).unwrap_or_else(|e| {
+                eprintln!("Failed to connect to server: {}", e);
+                exit(1);
+            });
+            let events = client.watch(prefix.into_bytes()).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(1);
+            });
+            for event in events {
+                if let Err(e) = event.and_then(|event| Ok(print_event(&event)?)) {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// Prints an event as `<seq> set <key> <value>` or `<seq> rm <key>`.
+///
+/// Keys and values may be binary, so like `get` this writes them out
+/// unmodified.
+fn print_event(event: &Event) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    match event {
+        Event::Set { key, value, seq } => {
+            write!(stdout, "{seq} set ")?;
+            stdout.write_all(key)?;
+            stdout.write_all(b" ")?;
+            stdout.write_all(value)?;
+        }
+        Event::Remove { key, seq } => {
+            write!(stdout, "{seq} rm ")?;
+            stdout.write_all(key)?;
+        }
     }
+    writeln!(stdout)
 }
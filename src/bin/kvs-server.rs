@@ -1,14 +1,15 @@
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::{error, info};
 
 use kvs::{
-    KvError, KvStore, KvsEngine, KvsServer, Result, SharedQueueThreadPool, SledKvsEngine,
-    ThreadPool,
+    Compression, KvError, KvStore, KvStoreOptions, KvsEngine, KvsServer, Result,
+    SharedQueueThreadPool, SledKvsEngine, SyncPolicy, ThreadPool,
 };
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4000";
@@ -17,6 +18,9 @@ const DEFAULT_ENGINE: &str = "kvs";
 #[derive(Parser)]
 #[command(name = "kvs-server", version, about = "A key-value store server")]
 struct Cli {
+    #[command(subcommand)]
+    admin: Option<Admin>,
+
     /// Server listening address
     #[arg(long, default_value = DEFAULT_ADDR, value_name = "IP-PORT")]
     addr: SocketAddr,
@@ -24,6 +28,60 @@ struct Cli {
     /// Storage engine: "kvs" or "sled"
     #[arg(long, value_name = "ENGINE-NAME")]
     engine: Option<String>,
+
+    /// Durability policy for the kvs engine: "never", "always", "<N>ms" or "<N>writes"
+    #[arg(long, default_value = "never", value_name = "POLICY")]
+    sync: SyncPolicy,
+
+    /// Stale bytes that trigger a compaction (kvs engine only)
+    #[arg(long, value_name = "BYTES")]
+    compaction_threshold: Option<u64>,
+
+    /// Stale fraction of the log (0-1) that triggers a compaction (kvs engine only)
+    #[arg(long, value_name = "RATIO", value_parser = parse_compaction_ratio)]
+    compaction_ratio: Option<f64>,
+
+    /// Size at which the active log rolls to a new file (kvs engine only)
+    #[arg(long, value_name = "BYTES")]
+    max_file_size: Option<u64>,
+
+    /// Buffer size of each log reader (kvs engine only)
+    #[arg(long, value_name = "BYTES")]
+    read_buffer_size: Option<usize>,
+
+    /// Byte budget of the in-memory value cache; 0 disables it (kvs engine only)
+    #[arg(long, value_name = "BYTES")]
+    cache_size: Option<usize>,
+
+    /// Codec for new records: "none", "lz4" or "zstd" (kvs engine only)
+    #[arg(long, default_value = "none", value_name = "CODEC")]
+    compression: Compression,
+
+    /// Records smaller than this are not compressed (kvs engine only)
+    #[arg(long, value_name = "BYTES")]
+    compression_min_size: Option<usize>,
+
+    /// File holding the base64 keys records are encrypted with, the current one first
+    /// (kvs engine only; defaults to $KVS_ENCRYPTION_KEY)
+    #[arg(long, value_name = "PATH")]
+    encryption_key_file: Option<PathBuf>,
+}
+
+/// Administrative commands run on the store in the current directory
+/// instead of serving it (kvs engine only). They refuse to run while a
+/// server holds the store.
+#[derive(Subcommand)]
+enum Admin {
+    /// Write a consistent copy of the store to a directory
+    Backup {
+        /// Directory to write the backup to
+        dir: PathBuf,
+    },
+    /// Recreate the store from a backup; the current directory must not hold a store
+    Restore {
+        /// Directory holding the backup
+        dir: PathBuf,
+    },
 }
 
 fn main() {
@@ -41,7 +99,15 @@ fn main() {
 }
 
 fn run(cli: Cli) -> Result<()> {
-    let engine_name = resolve_engine(cli.engine)?;
+    let engine_name = resolve_engine(cli.engine.clone())?;
+    if let Some(admin) = &cli.admin {
+        if engine_name != "kvs" {
+            return Err(KvError::StringError(
+                "Backup and restore need the kvs engine".to_owned(),
+            ));
+        }
+        return run_admin(admin, &cli);
+    }
     let num_cpus = num_cpus::get() as u32;
 
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
@@ -50,7 +116,7 @@ fn run(cli: Cli) -> Result<()> {
 
     match engine_name.as_str() {
         "kvs" => run_with_engine(
-            KvStore::open(current_dir()?)?,
+            KvStore::open_with(current_dir()?, kvs_options(&cli)?)?,
             SharedQueueThreadPool::new(num_cpus)?,
             cli.addr,
         ),
@@ -63,6 +129,57 @@ fn run(cli: Cli) -> Result<()> {
     }
 }
 
+fn run_admin(admin: &Admin, cli: &Cli) -> Result<()> {
+    match admin {
+        Admin::Backup { dir } => {
+            let store = KvStore::open_with(current_dir()?, kvs_options(cli)?)?;
+            store.backup_to(dir)?;
+            info!("Backed up to {}", dir.display());
+        }
+        Admin::Restore { dir } => {
+            KvStore::restore_from_with(current_dir()?, dir, kvs_options(cli)?)?;
+            info!("Restored from {}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// Builds the `KvStore` options from the command line.
+fn kvs_options(cli: &Cli) -> Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new()
+        .sync_policy(cli.sync)
+        .compression(cli.compression);
+    if let Some(bytes) = cli.compaction_threshold {
+        options = options.compaction_threshold(bytes);
+    }
+    if let Some(ratio) = cli.compaction_ratio {
+        options = options.compaction_ratio(ratio);
+    }
+    if let Some(bytes) = cli.max_file_size {
+        options = options.max_file_size(bytes);
+    }
+    if let Some(bytes) = cli.read_buffer_size {
+        options = options.read_buffer_size(bytes);
+    }
+    if let Some(bytes) = cli.cache_size {
+        options = options.cache_size(bytes);
+    }
+    if let Some(bytes) = cli.compression_min_size {
+        options = options.compression_min_size(bytes);
+    }
+    options.with_encryption_keys_from(cli.encryption_key_file.as_deref())
+}
+
+/// Parses a compaction ratio, which must be greater than 0 and at most 1.
+fn parse_compaction_ratio(s: &str) -> Result<f64> {
+    match s.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio <= 1.0 => Ok(ratio),
+        _ => Err(KvError::StringError(format!(
+            "Invalid compaction ratio: {s}. Must be greater than 0 and at most 1."
+        ))),
+    }
+}
+
 fn run_with_engine<E: KvsEngine, P: ThreadPool>(
     engine: E,
     pool: P,
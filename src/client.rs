@@ -1,13 +1,18 @@
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 
 use serde::Deserialize;
 use serde_json::de::IoRead;
 use serde_json::Deserializer;
 
 use crate::common::{Request, Response};
+use crate::engines::{export, Event, WriteBatch};
 use crate::{KvError, Result};
 
+/// Number of pairs `import` sends in each batch.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
 /// The client of a key-value store.
 pub struct KvsClient {
     reader: Deserializer<IoRead<TcpStream>>,
@@ -25,39 +30,262 @@ impl KvsClient {
         })
     }
 
-    /// Sets a key-value pair on the server.
+    /// Sets a string key-value pair on the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Request::Set { key, value };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
-        match Response::deserialize(&mut self.reader)? {
-            Response::Ok(_) => Ok(()),
-            Response::Err(msg) => Err(KvError::StringError(msg)),
-        }
+    /// Sets a string key-value pair on the server that expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
     }
 
-    /// Gets the value for a key from the server.
+    /// Gets the string value for a string key from the server.
+    ///
+    /// Fails with `KvError::Utf8` if the value is not valid UTF-8.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get { key };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
 
-        match Response::deserialize(&mut self.reader)? {
+    /// Gets the string value for a string key from the server along with
+    /// its version.
+    ///
+    /// Fails with `KvError::Utf8` if the value is not valid UTF-8.
+    pub fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        self.get_versioned_bytes(key.into_bytes())?
+            .map(|(value, version)| Ok((String::from_utf8(value)?, version)))
+            .transpose()
+    }
+
+    /// Removes a string key from the server.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Sets string `key` to `new` on the server if it currently holds
+    /// `expected`.
+    ///
+    /// `None` stands for an absent key. Returns whether the swap was made.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Sets a key-value pair on the server.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.send_set(key, value, None)
+    }
+
+    /// Sets a key-value pair on the server that expires after `ttl`.
+    pub fn set_with_ttl_bytes(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.send_set(key, value, Some(ttl.as_millis() as u64))
+    }
+
+    /// Gets the value for a key from the server.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get { key })? {
             Response::Ok(value) => Ok(value),
-            Response::Err(msg) => Err(KvError::StringError(msg)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the value for a key from the server along with its version.
+    pub fn get_versioned_bytes(&mut self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        match self.request(&Request::GetVersioned { key })? {
+            Response::Versioned { value, version } => Ok(Some((value, version))),
+            Response::Ok(None) => Ok(None),
+            response => Err(unexpected(response)),
         }
     }
 
     /// Removes a key from the server.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let request = Request::Remove { key };
-        serde_json::to_writer(&mut self.writer, &request)?;
-        self.writer.flush()?;
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.expect_ok(&Request::Remove { key })
+    }
+
+    /// Sets `key` to `new` on the server if it currently holds `expected`.
+    ///
+    /// `None` stands for an absent key. Returns whether the swap was made.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        match self.request(&Request::Cas { key, expected, new })? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Applies every write of `batch` on the server atomically.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.expect_ok(&Request::WriteBatch(batch))
+    }
+
+    /// Starts a transaction on this connection.
+    ///
+    /// Until [`commit`](Self::commit) or [`abort`](Self::abort), reads and
+    /// writes made through this client go through the transaction, and
+    /// writes take effect only on commit. Versioned gets, compare-and-swap
+    /// and TTLs are not available inside a transaction.
+    pub fn begin(&mut self) -> Result<()> {
+        self.expect_ok(&Request::Begin)
+    }
 
-        match Response::deserialize(&mut self.reader)? {
+    /// Commits the transaction of this connection.
+    ///
+    /// Fails with `KvError::Conflict` if a key it read was changed by
+    /// another write in the meantime; the transaction is then discarded.
+    pub fn commit(&mut self) -> Result<()> {
+        match self.request(&Request::Commit)? {
             Response::Ok(_) => Ok(()),
-            Response::Err(msg) => Err(KvError::StringError(msg)),
+            Response::Conflict => Err(KvError::Conflict),
+            response => Err(unexpected(response)),
         }
     }
+
+    /// Discards the transaction of this connection.
+    pub fn abort(&mut self) -> Result<()> {
+        self.expect_ok(&Request::Abort)
+    }
+
+    /// Writes every live pair on the server to `writer` in the JSON Lines
+    /// format of [`KvsEngine::export`], returning the number of pairs.
+    ///
+    /// If writing to `writer` fails, the rest of the export is left unread
+    /// and the client should not be used any further.
+    ///
+    /// [`KvsEngine::export`]: crate::KvsEngine::export
+    pub fn export<W: Write>(&mut self, writer: W) -> Result<u64> {
+        self.send(&Request::Export)?;
+        let reader = &mut self.reader;
+        let pairs = std::iter::from_fn(|| match receive(reader) {
+            Ok(Response::Pair { key, value }) => Some(Ok((key, value))),
+            Ok(Response::Ok(None)) => None,
+            Ok(response) => Some(Err(unexpected(response))),
+            Err(e) => Some(Err(e)),
+        });
+        export::write_pairs(pairs, writer)
+    }
+
+    /// Sets every pair of a dump read from `reader` on the server,
+    /// returning the number of pairs.
+    ///
+    /// Pairs are sent in batches of up to `IMPORT_BATCH_SIZE`, each applied
+    /// atomically, so a failure can leave the batches before it imported.
+    pub fn import<R: BufRead>(&mut self, reader: R) -> Result<u64> {
+        let mut count = 0;
+        let mut batch = WriteBatch::new();
+        for pair in export::read_pairs(reader) {
+            let (key, value) = pair?;
+            batch.set_bytes(key, value);
+            count += 1;
+            if batch.len() == IMPORT_BATCH_SIZE {
+                self.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            self.write_batch(batch)?;
+        }
+        Ok(count)
+    }
+
+    /// Watches the writes to keys starting with `prefix`.
+    ///
+    /// The connection is dedicated to the watch from then on, so this
+    /// consumes the client. Only writes made after the server acknowledges
+    /// the watch are reported.
+    pub fn watch(mut self, prefix: Vec<u8>) -> Result<Watch> {
+        self.expect_ok(&Request::Watch { prefix })?;
+        Ok(Watch {
+            reader: self.reader,
+        })
+    }
+
+    /// Sends a `Set` request with an optional time to live.
+    fn send_set(&mut self, key: Vec<u8>, value: Vec<u8>, ttl_millis: Option<u64>) -> Result<()> {
+        let request = Request::Set {
+            key,
+            value,
+            ttl_millis,
+        };
+        self.expect_ok(&request)
+    }
+
+    /// Sends a request that succeeds without a value.
+    fn expect_ok(&mut self, request: &Request) -> Result<()> {
+        match self.request(request)? {
+            Response::Ok(_) => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sends a request and reads the server's response.
+    ///
+    /// An error reported by the server is returned as `KvError::StringError`.
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        self.send(request)?;
+        receive(&mut self.reader)
+    }
+
+    /// Sends a request without waiting for the response.
+    fn send(&mut self, request: &Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, request)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads a response from the server, returning an error it reports as
+/// `KvError::StringError`.
+fn receive(reader: &mut Deserializer<IoRead<TcpStream>>) -> Result<Response> {
+    match Response::deserialize(reader)? {
+        Response::Err(msg) => Err(KvError::StringError(msg)),
+        response => Ok(response),
+    }
+}
+
+/// Stream of writes returned by [`KvsClient::watch`].
+///
+/// Iterating blocks until the server sends the next event, and ends when
+/// the server closes the connection. If the server drops the watch because
+/// events were read too slowly, that is reported as an error first.
+pub struct Watch {
+    reader: Deserializer<IoRead<TcpStream>>,
+}
+
+impl Iterator for Watch {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        match Response::deserialize(&mut self.reader) {
+            Ok(Response::Event(event)) => Some(Ok(event)),
+            Ok(Response::Err(msg)) => Some(Err(KvError::StringError(msg))),
+            Ok(response) => Some(Err(unexpected(response))),
+            Err(e) if e.is_eof() => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// Builds the error for a response that does not match the request.
+fn unexpected(response: Response) -> KvError {
+    KvError::StringError(format!("Unexpected response: {response:?}"))
 }
@@ -1,24 +1,83 @@
 use serde::{Deserialize, Serialize};
 
+use crate::engines::{Event, WriteBatch};
+
 /// Request sent from client to server.
+///
+/// Keys and values are arbitrary bytes. On the wire they are JSON strings
+/// when they are valid UTF-8 and `{"base64": "..."}` objects otherwise.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
     /// Set a key-value pair.
     Set {
         /// The key to set.
-        key: String,
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
         /// The value to associate with the key.
-        value: String,
+        #[serde(with = "crate::encoding")]
+        value: Vec<u8>,
+        /// Time to live of the key in milliseconds, if it should expire.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_millis: Option<u64>,
     },
     /// Get the value for a key.
     Get {
         /// The key to look up.
-        key: String,
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    /// Get the value for a key along with its version.
+    GetVersioned {
+        /// The key to look up.
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
     },
     /// Remove a key.
     Remove {
         /// The key to remove.
-        key: String,
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+    /// Set a key to a new value if it currently holds the expected one.
+    Cas {
+        /// The key to swap.
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        /// The value the key must hold, or `None` if it must be absent.
+        #[serde(with = "crate::encoding::option")]
+        expected: Option<Vec<u8>>,
+        /// The value to store, or `None` to remove the key.
+        #[serde(with = "crate::encoding::option")]
+        new: Option<Vec<u8>>,
+    },
+    /// Apply every write of a batch atomically.
+    WriteBatch(WriteBatch),
+    /// Start a transaction on this connection.
+    ///
+    /// Until it is committed or aborted, `Get`, `Set` and `Remove` go
+    /// through the transaction, and `GetVersioned`, `Cas` and `WriteBatch`
+    /// are refused.
+    Begin,
+    /// Commit the transaction of this connection.
+    Commit,
+    /// Discard the transaction of this connection.
+    Abort,
+    /// Dump every live pair.
+    ///
+    /// The server answers with a `Pair` for each pair in key order, read
+    /// by `KvsEngine::export_pairs`, followed by `Ok`.
+    Export,
+    /// Stream the writes to keys starting with `prefix`.
+    ///
+    /// After the `Ok` acknowledgement the server sends an `Event` for
+    /// every later write to a matching key until the client disconnects,
+    /// and reads no further requests from the connection. If the client
+    /// falls too far behind, the server sends an `Err` and closes the
+    /// connection.
+    Watch {
+        /// The prefix of the keys to watch.
+        #[serde(with = "crate::encoding")]
+        prefix: Vec<u8>,
     },
 }
 
@@ -26,7 +85,31 @@ pub enum Request {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     /// Operation succeeded, optionally with a value.
-    Ok(Option<String>),
+    Ok(#[serde(with = "crate::encoding::option")] Option<Vec<u8>>),
+    /// A value found by `GetVersioned`, with its version. A missing key
+    /// is answered with `Ok(None)`.
+    Versioned {
+        /// The value of the key.
+        #[serde(with = "crate::encoding")]
+        value: Vec<u8>,
+        /// The version of the value.
+        version: u64,
+    },
+    /// Result of a compare-and-swap: whether the swap was made.
+    Swapped(bool),
+    /// A transaction failed to commit because of a conflicting write.
+    Conflict,
+    /// A write to a watched key.
+    Event(Event),
+    /// One pair of an `Export`.
+    Pair {
+        /// The key.
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        /// The value of the key.
+        #[serde(with = "crate::encoding")]
+        value: Vec<u8>,
+    },
     /// Operation failed with an error message.
     Err(String),
 }
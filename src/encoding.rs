@@ -0,0 +1,63 @@
+//! Serde representation of binary keys and values.
+//!
+//! Bytes that are valid UTF-8 are written as a plain string, so logs and
+//! requests holding text look the same as before keys and values became
+//! binary. Any other bytes are written as `{"base64": "..."}`.
+//!
+//! Use with `#[serde(with = "crate::encoding")]` on `Vec<u8>` fields and
+//! `#[serde(with = "crate::encoding::option")]` on `Option<Vec<u8>>` ones.
+
+use std::str;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    Text(String),
+    Binary { base64: String },
+}
+
+pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    match str::from_utf8(bytes) {
+        Ok(text) => serializer.serialize_str(text),
+        Err(_) => Repr::Binary {
+            base64: STANDARD.encode(bytes),
+        }
+        .serialize(serializer),
+    }
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    match Repr::deserialize(deserializer)? {
+        Repr::Text(text) => Ok(text.into_bytes()),
+        Repr::Binary { base64 } => STANDARD.decode(base64).map_err(D::Error::custom),
+    }
+}
+
+/// The same representation for optional values; `None` is `null`.
+pub(crate) mod option {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(with = "super")] Vec<u8>);
+
+    pub(crate) fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => super::serialize(bytes, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(bytes)| bytes))
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use super::to_millis;
+
+/// A group of writes applied atomically by [`KvsEngine::write_batch`].
+///
+/// [`KvsEngine::write_batch`]: super::KvsEngine::write_batch
+///
+/// ```no_run
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+///
+/// let store = KvStore::open("data")?;
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("from".to_owned(), "90".to_owned())
+///     .set("to".to_owned(), "110".to_owned());
+/// store.write_batch(batch)?;
+/// # Ok::<(), kvs::KvError>(())
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+/// A single write of a `WriteBatch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        #[serde(with = "crate::encoding")]
+        value: Vec<u8>,
+        /// Expiry time in milliseconds since the Unix epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a write of string `value` to string `key`.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Adds a removal of string `key`.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Adds a write of `value` to `key`.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            expires_at: None,
+        });
+        self
+    }
+
+    /// Adds a write of `value` to `key` that expires at `expires_at`.
+    ///
+    /// Unlike [`KvsEngine::set_with_ttl_bytes`], the expiry time is given
+    /// outright, so that a copied key keeps the one it had. A time in the
+    /// past writes a key that is already expired.
+    ///
+    /// [`KvsEngine::set_with_ttl_bytes`]: super::KvsEngine::set_with_ttl_bytes
+    pub fn set_expiring_bytes(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: SystemTime,
+    ) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            expires_at: Some(to_millis(expires_at)),
+        });
+        self
+    }
+
+    /// Adds a removal of `key`.
+    ///
+    /// Unlike [`KvsEngine::remove_bytes`], removing a key that does not
+    /// exist is not an error; the removal is simply skipped.
+    ///
+    /// [`KvsEngine::remove_bytes`]: super::KvsEngine::remove_bytes
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
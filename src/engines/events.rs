@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// A write to an engine, as delivered by [`KvsEngine::subscribe`].
+///
+/// [`KvsEngine::subscribe`]: super::KvsEngine::subscribe
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    /// A key was set to a value.
+    Set {
+        /// The key that was set.
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        /// The new value of the key.
+        #[serde(with = "crate::encoding")]
+        value: Vec<u8>,
+        /// Sequence number of the write.
+        seq: u64,
+    },
+    /// A key was removed.
+    Remove {
+        /// The key that was removed.
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        /// Sequence number of the write.
+        seq: u64,
+    },
+}
+
+impl Event {
+    /// Returns the key the event applies to.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Event::Set { key, .. } | Event::Remove { key, .. } => key,
+        }
+    }
+
+    /// Returns the sequence number of the write.
+    pub fn seq(&self) -> u64 {
+        match self {
+            Event::Set { seq, .. } | Event::Remove { seq, .. } => *seq,
+        }
+    }
+}
+
+/// Source of the live events of a `Subscription`.
+pub(crate) trait EventSource: Send {
+    /// Waits for the next event, for at most `timeout` if one is given.
+    ///
+    /// Returns `None` on timeout or once no more events can arrive.
+    fn next_event(&mut self, timeout: Option<Duration>) -> Option<Event>;
+
+    /// Whether the engine stopped sending events because the subscriber
+    /// fell too far behind.
+    fn lagged(&self) -> bool {
+        false
+    }
+}
+
+/// Stream of events returned by [`KvsEngine::subscribe`].
+///
+/// Iterating blocks until the next event arrives, and ends once every
+/// handle to the engine has been dropped or the subscriber has fallen too
+/// far behind (see [`Subscription::lagged`]).
+///
+/// [`KvsEngine::subscribe`]: super::KvsEngine::subscribe
+pub struct Subscription {
+    /// Past events, in order, delivered before the live ones.
+    history: std::vec::IntoIter<Event>,
+    live: Box<dyn EventSource>,
+}
+
+impl Subscription {
+    pub(crate) fn new(history: Vec<Event>, live: impl EventSource + 'static) -> Self {
+        Self {
+            history: history.into_iter(),
+            live: Box::new(live),
+        }
+    }
+
+    /// Waits up to `timeout` for the next event.
+    ///
+    /// Returns `None` if no event arrived in time or the engine is gone.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.history
+            .next()
+            .or_else(|| self.live.next_event(Some(timeout)))
+    }
+
+    /// Whether the engine dropped the subscription because too many events
+    /// were left unread. The events sent before that are still delivered,
+    /// then the subscription ends; later writes are missed.
+    pub fn lagged(&self) -> bool {
+        self.live.lagged()
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.history.next().or_else(|| self.live.next_event(None))
+    }
+}
@@ -0,0 +1,52 @@
+//! JSON Lines representation of a key-value dump.
+//!
+//! Each line holds one pair as `{"key":..,"value":..}`, with keys and
+//! values encoded as in requests (see `crate::encoding`).
+
+use std::io::{BufRead, BufWriter, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvError, Result};
+
+/// One line of a dump.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    #[serde(with = "crate::encoding")]
+    key: Vec<u8>,
+    #[serde(with = "crate::encoding")]
+    value: Vec<u8>,
+}
+
+/// Writes `pairs` to `writer`, one line each, and returns how many were
+/// written.
+pub(crate) fn write_pairs<W: Write>(
+    pairs: impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    writer: W,
+) -> Result<u64> {
+    let mut writer = BufWriter::new(writer);
+    let mut count = 0;
+    for pair in pairs {
+        let (key, value) = pair?;
+        serde_json::to_writer(&mut writer, &Line { key, value })?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Reads the pairs of a dump from `reader`, skipping blank lines.
+pub(crate) fn read_pairs<R: BufRead>(
+    reader: R,
+) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+    reader
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(n, line)| {
+            let line: Line = serde_json::from_str(&line?)
+                .map_err(|e| KvError::StringError(format!("Invalid line {}: {}", n + 1, e)))?;
+            Ok((line.key, line.value))
+        })
+}
@@ -0,0 +1,192 @@
+//! Online backup and restore.
+//!
+//! A backup is taken under the writer `Mutex`, which fixes the set of
+//! generations and the length of the active log at one point in time. The
+//! generations are pinned like a snapshot's so that compaction cannot
+//! delete them while they are copied. Sealed generations never change, so
+//! they are hard-linked into the backup when the file system allows it;
+//! the active log is copied up to the recorded length.
+//!
+//! A backup directory holds the same files as a store, so it can also be
+//! opened directly.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::format::marker_path;
+use super::hint::hint_path;
+use super::lock::{lock_path, DirLock};
+use super::snapshot::{self, retired_path};
+use super::{log_path, sorted_gen_list, KvStore, KvStoreOptions};
+use crate::{KvError, Result};
+
+impl KvStore {
+    /// Writes a consistent copy of the store to `dir` while it stays open.
+    ///
+    /// The copy holds every write committed before the call and none made
+    /// after. `dir` is created if needed and must not hold a store yet.
+    pub fn backup_to(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        ensure_no_store(dir)?;
+        let (gens, (active_gen, active_len), _pin) = self.exclusive(|writer| {
+            writer.writer.flush()?;
+            // Compaction changes the format under the same lock.
+            writer.encoding.format.write_marker(dir)?;
+            let gens = writer.gens.clone();
+            let active = (writer.current_gen, writer.writer.pos);
+            Ok((gens.clone(), active, self.pins.hold(gens)))
+        })?;
+
+        for gen in gens {
+            if gen == active_gen {
+                let mut log = snapshot::open_log(&self.path, gen)?.take(active_len);
+                let mut copy = File::create(log_path(dir, gen))?;
+                io::copy(&mut log, &mut copy)?;
+                copy.sync_all()?;
+                continue;
+            }
+            // A pinned generation that compaction has retired keeps its
+            // records under the retired name.
+            let target = log_path(dir, gen);
+            match link_or_copy(&log_path(&self.path, gen), &target) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    link_or_copy(&retired_path(&self.path, gen), &target)?
+                }
+                result => result?,
+            }
+            // Compaction removes hints without regard to pins, and a
+            // generation without one is simply replayed.
+            ignore_missing(link_or_copy(
+                &hint_path(&self.path, gen),
+                &hint_path(dir, gen),
+            ))?;
+        }
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Restores the backup in `backup` to `path` and opens the store.
+    ///
+    /// `path` is created if needed and must not hold a store yet, nor be
+    /// held by an open one. If the restore fails, the files it copied are
+    /// removed again; if it is interrupted, clear `path` before trying
+    /// again.
+    pub fn restore_from(path: impl Into<PathBuf>, backup: impl AsRef<Path>) -> Result<KvStore> {
+        Self::restore_from_with(path, backup, KvStoreOptions::default())
+    }
+
+    /// Restores the backup in `backup` to `path` like
+    /// [`KvStore::restore_from`] and opens the store with `options`.
+    ///
+    /// Every record is read back with the keys in `options` before the
+    /// store is opened, so a backup that is damaged or that the keys do not
+    /// decrypt fails the restore.
+    pub fn restore_from_with(
+        path: impl Into<PathBuf>,
+        backup: impl AsRef<Path>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
+        let path = path.into();
+        let backup = backup.as_ref();
+        options.validate()?;
+        let gens = sorted_gen_list(backup)?;
+        if gens.is_empty() {
+            return Err(KvError::StringError(format!(
+                "{} does not hold a backup",
+                backup.display()
+            )));
+        }
+        let created = !path.exists();
+        fs::create_dir_all(&path)?;
+        let mut copied = Vec::new();
+        if !lock_path(&path).exists() {
+            copied.push(lock_path(&path));
+        }
+        let lock = DirLock::acquire(&path)?;
+        ensure_no_store(&path)?;
+
+        let result = copy_backup(&path, backup, &gens, &mut copied).and_then(|()| {
+            if KvStore::verify_with(&path, &options)?.is_corrupt() {
+                return Err(KvError::StringError(format!(
+                    "{} is damaged or not readable with the given keys",
+                    backup.display()
+                )));
+            }
+            KvStore::open_inner(path.clone(), options, lock).map(|(store, _)| store)
+        });
+        if result.is_err() {
+            // Best effort: the restore's own error is the one to report.
+            if created {
+                let _ = fs::remove_dir_all(&path);
+            } else {
+                for file in &copied {
+                    let _ = fs::remove_file(file);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Copies the logs, hints and format marker of `backup` to `path`, adding
+/// every file it creates to `copied`.
+fn copy_backup(path: &Path, backup: &Path, gens: &[u64], copied: &mut Vec<PathBuf>) -> Result<()> {
+    // Logs are copied under a temporary name and renamed once all of them
+    // are complete, so that a failed copy leaves no partial store. A backup
+    // of a store that predates the format marker has none.
+    copied.push(marker_path(path));
+    ignore_missing(fs::copy(marker_path(backup), marker_path(path)))?;
+    for &gen in gens {
+        copied.push(restoring_path(path, gen));
+        fs::copy(log_path(backup, gen), restoring_path(path, gen))?;
+        File::open(restoring_path(path, gen))?.sync_all()?;
+        copied.push(hint_path(path, gen));
+        ignore_missing(fs::copy(hint_path(backup, gen), hint_path(path, gen)))?;
+    }
+    for &gen in gens {
+        copied.push(log_path(path, gen));
+        fs::rename(restoring_path(path, gen), log_path(path, gen))?;
+    }
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Fails if `dir` already holds the logs of a store.
+fn ensure_no_store(dir: &Path) -> Result<()> {
+    if sorted_gen_list(dir)?.is_empty() {
+        Ok(())
+    } else {
+        Err(KvError::StringError(format!(
+            "{} already holds a store",
+            dir.display()
+        )))
+    }
+}
+
+/// Hard-links `source` to `target`, or copies it where links are not
+/// supported.
+fn link_or_copy(source: &Path, target: &Path) -> io::Result<()> {
+    match fs::hard_link(source, target) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            fs::copy(source, target)?;
+        }
+        result => result?,
+    }
+    // Sealed generations are not synced under every policy.
+    File::open(target)?.sync_all()
+}
+
+/// Treats a missing file as success.
+fn ignore_missing<T>(result: io::Result<T>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result.map(drop),
+    }
+}
+
+/// Returns the temporary path a log is restored to.
+fn restoring_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{gen}.restoring"))
+}
@@ -0,0 +1,217 @@
+//! In-memory cache of values read from the logs.
+//!
+//! Values are cached by the position of their record rather than by key.
+//! A record never changes once written, so an entry can never go stale: a
+//! `set` or `remove` points the index at a new position, and the entries of
+//! older positions simply stop being looked up and age out. Compaction,
+//! which moves every live record, drops the entries of the generations it
+//! deletes.
+//!
+//! The cache is split into shards, each behind its own `Mutex` and evicting
+//! with the CLOCK algorithm: a hit only sets a flag on the entry, and the
+//! clock hand clears flags until it finds an entry that was not used since
+//! its last pass.
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::{CommandPos, KvStore};
+
+/// Number of independently locked shards.
+const SHARDS: usize = 16;
+
+/// Bytes charged for an entry on top of its value, for its bookkeeping.
+const ENTRY_OVERHEAD: usize = mem::size_of::<Entry>() + mem::size_of::<((u64, u64), usize)>();
+
+/// Counters of a store's value cache, returned by
+/// [`KvStore::cache_stats`](crate::KvStore::cache_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from the cache.
+    pub hits: u64,
+    /// Reads that went to disk while the cache was enabled.
+    pub misses: u64,
+    /// Number of cached values.
+    pub entries: u64,
+    /// Bytes charged against the budget by the cached values.
+    pub bytes: u64,
+}
+
+/// A sharded CLOCK cache of values, keyed by record position.
+pub(super) struct ValueCache {
+    budget: usize,
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Shard {
+    /// Slot of each cached position in `entries`.
+    slots: HashMap<(u64, u64), usize>,
+    entries: Vec<Entry>,
+    /// Next slot the clock hand looks at.
+    hand: usize,
+    bytes: usize,
+    budget: usize,
+}
+
+struct Entry {
+    pos: (u64, u64),
+    value: Vec<u8>,
+    /// Set on a hit, cleared by the clock hand.
+    referenced: bool,
+}
+
+impl Entry {
+    fn charge(&self) -> usize {
+        self.value.len() + ENTRY_OVERHEAD
+    }
+}
+
+impl ValueCache {
+    /// Creates a cache holding at most `budget` bytes. A budget of 0
+    /// disables it.
+    pub(super) fn new(budget: usize) -> Self {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    budget: budget / SHARDS,
+                    ..Shard::default()
+                })
+            })
+            .collect();
+        Self {
+            budget,
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached value of the record at `cmd_pos`.
+    pub(super) fn get(&self, cmd_pos: CommandPos) -> Option<Vec<u8>> {
+        if self.budget == 0 {
+            return None;
+        }
+        let pos = (cmd_pos.gen, cmd_pos.pos);
+        let mut shard = self.shard(pos).lock().unwrap();
+        let Some(&slot) = shard.slots.get(&pos) else {
+            drop(shard);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let entry = &mut shard.entries[slot];
+        entry.referenced = true;
+        let value = entry.value.clone();
+        drop(shard);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Caches `value` as the value of the record at `cmd_pos`, evicting
+    /// other values to stay within the budget. Values larger than a shard's
+    /// share of the budget are not cached.
+    pub(super) fn insert(&self, cmd_pos: CommandPos, value: &[u8]) {
+        let pos = (cmd_pos.gen, cmd_pos.pos);
+        let mut shard = self.shard(pos).lock().unwrap();
+        let charge = value.len() + ENTRY_OVERHEAD;
+        if charge > shard.budget || shard.slots.contains_key(&pos) {
+            return;
+        }
+        while shard.bytes + charge > shard.budget {
+            shard.evict();
+        }
+        let slot = shard.entries.len();
+        shard.entries.push(Entry {
+            pos,
+            value: value.to_vec(),
+            referenced: false,
+        });
+        shard.slots.insert(pos, slot);
+        shard.bytes += charge;
+    }
+
+    /// Drops the values of generations older than `gen`.
+    pub(super) fn remove_gens_before(&self, gen: u64) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            if shard.entries.is_empty() {
+                continue;
+            }
+            let shard = &mut *shard;
+            shard.entries.retain(|entry| entry.pos.0 >= gen);
+            shard.slots = shard
+                .entries
+                .iter()
+                .enumerate()
+                .map(|(slot, entry)| (entry.pos, slot))
+                .collect();
+            shard.bytes = shard.entries.iter().map(Entry::charge).sum();
+            shard.hand = 0;
+        }
+    }
+
+    /// Returns the current counters.
+    pub(super) fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        };
+        if self.budget > 0 {
+            for shard in &self.shards {
+                let shard = shard.lock().unwrap();
+                stats.entries += shard.entries.len() as u64;
+                stats.bytes += shard.bytes as u64;
+            }
+        }
+        stats
+    }
+
+    fn shard(&self, (gen, pos): (u64, u64)) -> &Mutex<Shard> {
+        // Records of one generation sit at nearby offsets, so mix the bits
+        // before picking a shard.
+        let hash = (pos ^ gen.rotate_left(32)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.shards[(hash >> 32) as usize % SHARDS]
+    }
+}
+
+impl KvStore {
+    /// Returns the hit and miss counters and the size of the value cache
+    /// enabled by [`KvStoreOptions::cache_size`].
+    ///
+    /// Counters are shared by every clone of the store and start at zero
+    /// when it is opened.
+    ///
+    /// [`KvStoreOptions::cache_size`]: crate::KvStoreOptions::cache_size
+    pub fn cache_stats(&self) -> CacheStats {
+        self.reader.cache.stats()
+    }
+}
+
+impl Shard {
+    /// Evicts the first entry the clock hand finds unreferenced, clearing
+    /// the flags of the referenced ones it passes.
+    fn evict(&mut self) {
+        loop {
+            if self.hand >= self.entries.len() {
+                self.hand = 0;
+            }
+            let entry = &mut self.entries[self.hand];
+            if mem::take(&mut entry.referenced) {
+                self.hand += 1;
+                continue;
+            }
+            let evicted = self.entries.swap_remove(self.hand);
+            self.slots.remove(&evicted.pos);
+            if let Some(moved) = self.entries.get(self.hand) {
+                self.slots.insert(moved.pos, self.hand);
+            }
+            self.bytes -= evicted.charge();
+            return;
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! Background compaction.
+//!
+//! Compaction runs on a dedicated thread so that writers crossing the
+//! compaction threshold do not stall. It proceeds in three steps:
+//!
+//! 1. Under the writer `Mutex`, roll the active log to a fresh generation
+//!    and take a snapshot of the index.
+//! 2. Without holding any lock, copy every live record of the snapshot
+//!    into a new generation and write its hint file, leaving expired ones
+//!    behind. Readers and writers keep running against the old generations
+//!    and the new active log.
+//! 3. Under the writer `Mutex` and the index write-lock, point every key
+//!    that was not overwritten in the meantime at its copy (or drop it if
+//!    it expired), then advance `safe_point` and delete the old
+//!    generations. Old generations still referenced by a snapshot are
+//!    retired instead of deleted (see the `snapshot` module). Cached values
+//!    of the old generations are dropped.
+//!
+//! Under `SyncPolicy::EveryNMillis` the same thread also syncs the active
+//! log once writes have waited out the interval, so that writes followed by
+//! a pause do not stay unsynced until the next one.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+use log::{debug, error};
+
+use super::cache::ValueCache;
+use super::format::{self, Encoding, Format};
+use super::hint::{self, HintEntry};
+use super::record::{self, Frame};
+use super::snapshot::Pins;
+use super::{
+    append_command, log_path, now_millis, write_command, BufReaderWithPos, BufWriterWithPos,
+    Command, CommandPos, Index, KvStoreWriter,
+};
+use crate::{KvError, Result};
+
+/// Handle to the background compaction thread of a store.
+///
+/// Shared by every clone of a `KvStore`. Dropping the last handle stops
+/// the thread, waiting for a compaction or sync in progress to finish.
+pub(super) struct Compactor {
+    tx: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Spawns the compaction thread for the store at `path`, syncing the
+    /// active log every `sync_interval` if one is given.
+    ///
+    /// The thread only holds weak references to the store's state, so it
+    /// does not keep the store alive.
+    pub(super) fn spawn(
+        path: Arc<PathBuf>,
+        index: Weak<RwLock<Index>>,
+        writer: Weak<Mutex<KvStoreWriter>>,
+        safe_point: Arc<AtomicU64>,
+        pins: Arc<Pins>,
+        cache: Arc<ValueCache>,
+        sync_interval: Option<Duration>,
+    ) -> Result<Self> {
+        // A single slot: requests made while one is pending are coalesced.
+        let (tx, rx) = channel::bounded::<()>(1);
+        let thread = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                let mut next_sync = sync_interval.map(|interval| Instant::now() + interval);
+                loop {
+                    let request = match next_sync {
+                        Some(deadline) => rx.recv_deadline(deadline),
+                        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    let (Some(index), Some(writer)) = (index.upgrade(), writer.upgrade()) else {
+                        return;
+                    };
+                    match request {
+                        Ok(()) => {
+                            if let Err(e) =
+                                compact(&path, &index, &writer, &safe_point, &pins, &cache)
+                            {
+                                error!("Compaction failed: {}", e);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    if let Some(interval) = sync_interval {
+                        next_sync = Some(sync_overdue(&writer, interval));
+                    }
+                }
+                debug!("Compaction thread shutting down");
+            })?;
+        Ok(Self {
+            tx: Some(tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Asks the compaction thread to run. Returns immediately.
+    pub(super) fn trigger(&self) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.try_send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // Closing the channel ends the thread's loop.
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Compaction thread panicked");
+            }
+        }
+    }
+}
+
+/// Syncs writes that have waited for the interval of the sync policy, and
+/// returns when the next ones will be due.
+fn sync_overdue(writer: &Mutex<KvStoreWriter>, interval: Duration) -> Instant {
+    let mut writer = writer.lock().unwrap();
+    if let Err(e) = writer.sync_overdue() {
+        error!("Periodic sync failed: {}", e);
+    }
+    let now = Instant::now();
+    match writer.last_sync + interval {
+        due if due > now => due,
+        _ => now + interval,
+    }
+}
+
+/// Compacts the log by copying only the latest values to a new generation.
+///
+/// Updates `safe_point` before deleting the old generations so reader
+/// threads can retry a lookup that raced the deletion and clean up stale
+/// file handles.
+fn compact(
+    path: &Path,
+    index: &RwLock<Index>,
+    writer: &Mutex<KvStoreWriter>,
+    safe_point: &AtomicU64,
+    pins: &Pins,
+    cache: &ValueCache,
+) -> Result<()> {
+    let (compaction_gen, live, encoding) = {
+        let mut writer = writer.lock().unwrap();
+        // A request queued while the previous compaction ran may be stale.
+        if writer.poisoned || !writer.needs_compaction() {
+            return Ok(());
+        }
+        let compaction_gen = writer.current_gen + 1;
+        let active_gen = compaction_gen + 1;
+        // A JSON store is upgraded here: the copy is written in the binary
+        // format, and so is everything appended from now on.
+        let target = Format::writing(writer.encoding.compression, writer.encoding.keys.encrypts());
+        writer.encoding.format = writer.encoding.format.upgrade(path, target)?;
+        writer.roll(path, active_gen)?;
+        // Bytes that go stale from now on are in generations that survive
+        // this compaction (or in the copy it is about to write).
+        writer.uncompacted = 0;
+        let live: Vec<(Vec<u8>, CommandPos)> = index
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+            .collect();
+        (compaction_gen, live, writer.encoding.clone())
+    };
+    debug!(
+        "Compacting {} live keys into generation {}",
+        live.len(),
+        compaction_gen
+    );
+
+    let moved = copy_live(path, compaction_gen, live, &encoding)?;
+    let compacted_len: u64 = moved
+        .iter()
+        .filter_map(|moved| moved.new_pos.map(|new_pos| new_pos.len))
+        .sum();
+
+    let mut writer = writer.lock().unwrap();
+    writer.gens.insert(compaction_gen);
+    writer.total += compacted_len;
+    {
+        let mut index = index.write().unwrap();
+        for Moved {
+            key,
+            old_pos,
+            new_pos,
+        } in moved
+        {
+            // Keys written since the snapshot already point at a newer
+            // record; their copy stays behind as stale data.
+            let Some(cmd_pos) = index.get_mut(&key) else {
+                continue;
+            };
+            if cmd_pos.gen != old_pos.gen || cmd_pos.pos != old_pos.pos {
+                continue;
+            }
+            match new_pos {
+                Some(new_pos) => *cmd_pos = new_pos,
+                None => {
+                    index.remove(&key);
+                }
+            }
+        }
+    }
+
+    // The records of removed keys go with the old generations, and with
+    // them possibly the highest sequence number handed out; record it so
+    // that it is not handed out again after a restart.
+    let next_seq = Command::NextSeq {
+        seq: writer.next_seq,
+    };
+    writer.check_poisoned()?;
+    let start = writer.writer.pos;
+    let appended = append_command(&mut writer, &next_seq);
+    let marker = match appended.and_then(|marker| writer.sync().map(|()| marker)) {
+        Ok(marker) => marker,
+        Err(e) => {
+            writer.undo_append(start);
+            return Err(e);
+        }
+    };
+    writer.total += marker.len;
+    writer.uncompacted += marker.len;
+
+    // Advance safe_point before the old generations go, so that a reader
+    // that finds one missing knows to retry against the index; cached
+    // values of those generations must not outlive them either.
+    safe_point.store(compaction_gen, Ordering::Release);
+    cache.remove_gens_before(compaction_gen);
+
+    let stale_gens: Vec<u64> = writer.gens.range(..compaction_gen).copied().collect();
+    for stale_gen in stale_gens {
+        writer.gens.remove(&stale_gen);
+        let stale_path = log_path(path, stale_gen);
+        writer.total = writer
+            .total
+            .saturating_sub(fs::metadata(&stale_path)?.len());
+        pins.remove_generation(stale_gen)?;
+        hint::remove_hint(path, stale_gen)?;
+    }
+
+    Ok(())
+}
+
+/// Where compaction moved the record of one key.
+struct Moved {
+    key: Vec<u8>,
+    old_pos: CommandPos,
+    /// Position of the copy, or `None` if the value had expired.
+    new_pos: Option<CommandPos>,
+}
+
+/// Copies the records of `live` into generation `gen`, encoded with
+/// `encoding`, except for values that have expired.
+///
+/// The records are written to a temporary file that is synced and renamed
+/// into place, so a crash never leaves a partial generation behind.
+fn copy_live(
+    path: &Path,
+    gen: u64,
+    live: Vec<(Vec<u8>, CommandPos)>,
+    encoding: &Encoding,
+) -> Result<Vec<Moved>> {
+    let tmp_path = path.join(format!("{gen}.compacting"));
+    let mut compaction_writer = BufWriterWithPos::new(File::create(&tmp_path)?)?;
+    let mut readers: HashMap<u64, BufReaderWithPos<File>> = HashMap::new();
+    let mut moved = Vec::with_capacity(live.len());
+    let mut hints = Vec::with_capacity(live.len());
+
+    let now = now_millis();
+
+    for (key, old_pos) in live {
+        if old_pos.is_expired(now) {
+            moved.push(Moved {
+                key,
+                old_pos,
+                new_pos: None,
+            });
+            continue;
+        }
+        let reader = match readers.entry(old_pos.gen) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let file = File::open(log_path(path, old_pos.gen)).map_err(|err| {
+                    if err.kind() == io::ErrorKind::NotFound {
+                        KvError::LogFileNotFound(old_pos.gen)
+                    } else {
+                        err.into()
+                    }
+                })?;
+                e.insert(BufReaderWithPos::new(file)?)
+            }
+        };
+        reader.seek(SeekFrom::Start(old_pos.pos))?;
+
+        let new_pos = compaction_writer.pos;
+        let len = copy_record(
+            reader.take(old_pos.len),
+            &mut compaction_writer,
+            old_pos,
+            encoding,
+        )?;
+        hints.push(HintEntry {
+            key: key.clone(),
+            pos: new_pos,
+            len,
+            expires_at: old_pos.expires_at,
+            seq: old_pos.seq,
+        });
+        moved.push(Moved {
+            key,
+            old_pos,
+            new_pos: Some(CommandPos {
+                gen,
+                pos: new_pos,
+                len,
+                expires_at: old_pos.expires_at,
+                seq: old_pos.seq,
+            }),
+        });
+    }
+
+    // The compacted file replaces the old generations, so it must be on
+    // disk before they are deleted regardless of the sync policy.
+    compaction_writer.sync_data()?;
+    drop(compaction_writer);
+    fs::rename(&tmp_path, log_path(path, gen))?;
+    hint::write_hint(path, gen, &hints, &encoding.keys)?;
+
+    Ok(moved)
+}
+
+/// Copies the record at `old_pos`, read from `reader`, to `writer`,
+/// re-encoding it if it was written differently from `encoding`. This is
+/// also how records move to a new encryption key. Returns the length of
+/// the copy.
+fn copy_record<R: Read, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    old_pos: CommandPos,
+    encoding: &Encoding,
+) -> Result<u64> {
+    let payload = match record::read_frame(&mut reader)? {
+        Frame::Record(payload) => payload,
+        _ => {
+            return Err(KvError::Corruption {
+                gen: old_pos.gen,
+                pos: old_pos.pos,
+            })
+        }
+    };
+    if encoding.is_current(&payload) {
+        return Ok(record::write_frame(writer, &payload)?);
+    }
+    let cmd = format::decode(&payload, &encoding.keys, old_pos.gen, old_pos.pos)?;
+    write_command(writer, &cmd, encoding)
+}
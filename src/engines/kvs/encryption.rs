@@ -0,0 +1,194 @@
+//! Encryption of record payloads at rest.
+//!
+//! Each payload is sealed on its own with XChaCha20-Poly1305 under a fresh
+//! random nonce, so records stay independently readable and compaction can
+//! still copy them unchanged:
+//!
+//! ```text
+//! +-------------+-----------------+-----------------+--------------------+
+//! | version: u8 | key id: [u8; 4] | nonce: [u8; 24] | ciphertext and tag |
+//! +-------------+-----------------+-----------------+--------------------+
+//! ```
+//!
+//! The version byte and key id are authenticated along with the payload.
+//! The key id tells which key a record was sealed with, so that a store
+//! keeps reading records sealed with a previous key after the key changes.
+//!
+//! Where a record lies, its generation and offset, is deliberately not
+//! authenticated. Compaction copies sealed records unchanged into a new
+//! generation at new offsets, which binding the position would rule out:
+//! every live record would have to be sealed again on every compaction. Nor would binding it protect much. Encryption keeps
+//! payloads secret and rejects any that were altered or forged, but it does
+//! not make the store's history tamper-proof: whoever can write to the data
+//! directory can already put back an older copy of a log, cut a log short
+//! or delete it, and each of these brings older values back just as moving
+//! a sealed record to the end of the active log would. Guarding against
+//! such rollback takes control over the directory, not the cipher.
+
+use std::fmt;
+use std::str::FromStr;
+
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{AeadCore, Key, KeyInit, XChaCha20Poly1305, XNonce};
+
+use crate::{KvError, Result};
+
+/// Length of the key id that identifies the key a record was sealed with.
+const KEY_ID_LEN: usize = 4;
+
+/// Length of an XChaCha20-Poly1305 nonce.
+const NONCE_LEN: usize = 24;
+
+/// Length of the Poly1305 authentication tag.
+const TAG_LEN: usize = 16;
+
+/// Offset of the ciphertext in a sealed payload.
+const HEADER_LEN: usize = 1 + KEY_ID_LEN + NONCE_LEN;
+
+/// A 256-bit key `KvStore` encrypts records with.
+///
+/// Parsed from its base64 encoding, e.g. the output of
+/// `head -c 32 /dev/urandom | base64`. The key itself is never printed.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: XChaCha20Poly1305,
+    id: [u8; KEY_ID_LEN],
+}
+
+impl EncryptionKey {
+    /// Creates a key from its raw bytes.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&bytes));
+        // The tag of an empty message identifies the key without revealing
+        // anything about it. Random nonces are never all zeros in practice.
+        let tag = cipher
+            .encrypt(&XNonce::default(), &[][..])
+            .expect("sealing an empty message cannot fail");
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&tag[..KEY_ID_LEN]);
+        Self { cipher, id }
+    }
+
+    /// Generates a random key.
+    pub fn generate() -> Self {
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        Self::from_bytes(key.into())
+    }
+}
+
+impl FromStr for EncryptionKey {
+    type Err = KvError;
+
+    /// Parses the base64 encoding of 32 bytes.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            KvError::StringError("Invalid encryption key: must be 32 bytes in base64".to_owned())
+        };
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(s.trim())
+            .map_err(|_| invalid())?;
+        let bytes = bytes.try_into().map_err(|_| invalid())?;
+        Ok(Self::from_bytes(bytes))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(")?;
+        for byte in self.id {
+            write!(f, "{byte:02x}")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Keys a store seals new records with and opens existing ones with.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Keyring {
+    /// Key new records are sealed with; they are written in plaintext
+    /// without one.
+    current: Option<EncryptionKey>,
+    /// Keys that only open records sealed before a key change.
+    previous: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    /// Creates a keyring that seals with `current` and also opens with
+    /// `previous`.
+    pub(super) fn new(current: Option<EncryptionKey>, previous: Vec<EncryptionKey>) -> Self {
+        Self { current, previous }
+    }
+
+    /// Whether new records are sealed.
+    pub(super) fn encrypts(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Whether the keyring holds no key at all.
+    pub(super) fn is_empty(&self) -> bool {
+        self.current.is_none() && self.previous.is_empty()
+    }
+
+    /// Seals `payload` with the current key behind the `version` byte.
+    pub(super) fn seal(&self, version: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        let key = self
+            .current
+            .as_ref()
+            .expect("sealing requires an encryption key");
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = Vec::with_capacity(HEADER_LEN + payload.len() + TAG_LEN);
+        sealed.push(version);
+        sealed.extend_from_slice(&key.id);
+        sealed.extend_from_slice(&nonce);
+        let ciphertext = key
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: payload,
+                    aad: &sealed[..1 + KEY_ID_LEN],
+                },
+            )
+            .map_err(|_| KvError::StringError("Failed to encrypt a record".to_owned()))?;
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// Opens a payload sealed by `seal`.
+    ///
+    /// Returns `None` if no key matches its key id or it fails to
+    /// authenticate.
+    pub(super) fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < HEADER_LEN {
+            return None;
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+        let key = self.key(&header[1..1 + KEY_ID_LEN])?;
+        key.cipher
+            .decrypt(
+                XNonce::from_slice(&header[1 + KEY_ID_LEN..]),
+                Payload {
+                    msg: ciphertext,
+                    aad: &header[..1 + KEY_ID_LEN],
+                },
+            )
+            .ok()
+    }
+
+    /// Whether `sealed` was sealed with the current key.
+    pub(super) fn is_current(&self, sealed: &[u8]) -> bool {
+        match &self.current {
+            Some(key) => sealed.get(1..1 + KEY_ID_LEN) == Some(&key.id[..]),
+            None => false,
+        }
+    }
+
+    /// Returns the key with id `id`.
+    fn key(&self, id: &[u8]) -> Option<&EncryptionKey> {
+        self.current
+            .iter()
+            .chain(&self.previous)
+            .find(|key| key.id == id)
+    }
+}
@@ -0,0 +1,400 @@
+//! Encoding of commands in log record payloads.
+//!
+//! Stores created before the binary format hold a JSON `Command` in each
+//! record. Newer stores use a compact binary payload instead:
+//!
+//! ```text
+//! +-------------+----------+--------------------------------+
+//! | version: u8 | kind: u8 | fields (varints and raw bytes) |
+//! +-------------+----------+--------------------------------+
+//! ```
+//!
+//! | kind | command       | fields                                            |
+//! |------|---------------|---------------------------------------------------|
+//! | 1    | `Set`         | seq, expires_at (0 if none), key len, key, value len, value |
+//! | 2    | `Remove`      | seq, key len, key                                 |
+//! | 3    | `BatchBegin`  | count                                             |
+//! | 4    | `BatchCommit` |                                                   |
+//! | 5    | `NextSeq`     | seq                                               |
+//!
+//! Integers are unsigned LEB128 varints. With compression enabled, a
+//! binary payload of at least the configured size is stored compressed,
+//! behind a header naming the codec:
+//!
+//! ```text
+//! +-------------+-----------+------------------------------------------+
+//! | version: u8 | codec: u8 | compressed binary payload, from the kind |
+//! +-------------+-----------+------------------------------------------+
+//! ```
+//!
+//! With an encryption key, the binary or compressed payload is sealed in
+//! turn, as described in the `encryption` module, behind version 4.
+//!
+//! A JSON payload starts with `{` or `"`, never with a version byte, so
+//! every record decodes without knowing which format wrote it.
+//!
+//! The `format` marker file holds the newest format the store may contain,
+//! so that a build which does not know it refuses to open the store. A
+//! store without a marker predates it and holds JSON only. Such a store
+//! keeps appending JSON until its first compaction, which bumps the marker
+//! and rewrites every live record in the binary format, unless encryption
+//! is enabled: since records must not be written in plaintext, the store
+//! is then upgraded on open and only its existing records wait for
+//! compaction.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::encryption::Keyring;
+use super::options::Compression;
+use super::Command;
+use crate::{KvError, Result};
+
+/// Record payload formats, numbered by version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Format {
+    /// A JSON `Command`.
+    Json = 1,
+    /// The binary encoding described above.
+    Binary = 2,
+    /// A binary payload that may be compressed.
+    Compressed = 3,
+    /// A binary or compressed payload that may be encrypted.
+    Encrypted = 4,
+}
+
+/// Kinds of binary records.
+const SET: u8 = 1;
+const REMOVE: u8 = 2;
+const BATCH_BEGIN: u8 = 3;
+const BATCH_COMMIT: u8 = 4;
+const NEXT_SEQ: u8 = 5;
+
+/// Codec ids of compressed payloads.
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+
+/// Compression level used for Zstandard.
+const ZSTD_LEVEL: i32 = 3;
+
+impl Format {
+    /// Returns the format of the store in `dir`.
+    ///
+    /// A new store, which has no logs yet, is created in `target`. A binary
+    /// store is upgraded to `target` if that is newer; a JSON one waits for
+    /// compaction unless `target` is encrypted.
+    pub(super) fn open(
+        dir: &Path,
+        has_logs: bool,
+        target: Format,
+        keys: &Keyring,
+    ) -> Result<Format> {
+        let format = match Format::read(dir)? {
+            Some(format) => format,
+            None if has_logs => Format::Json,
+            None => {
+                target.write_marker(dir)?;
+                return Ok(target);
+            }
+        };
+        check_keys(format, keys)?;
+        match format {
+            Format::Json if target < Format::Encrypted => Ok(format),
+            _ => format.upgrade(dir, target),
+        }
+    }
+
+    /// Reads the format marker of the store in `dir`, if it has one.
+    pub(super) fn read(dir: &Path) -> Result<Option<Format>> {
+        let marker = match fs::read_to_string(marker_path(dir)) {
+            Ok(marker) => marker,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let format = match marker.trim() {
+            "1" => Format::Json,
+            "2" => Format::Binary,
+            "3" => Format::Compressed,
+            "4" => Format::Encrypted,
+            other => {
+                return Err(KvError::StringError(format!(
+                    "Unsupported log format '{}'",
+                    other
+                )))
+            }
+        };
+        Ok(Some(format))
+    }
+
+    /// Returns the format records are written in with `compression`, and
+    /// encrypted if `encrypts` is set.
+    pub(super) fn writing(compression: Compression, encrypts: bool) -> Format {
+        match compression {
+            _ if encrypts => Format::Encrypted,
+            Compression::None => Format::Binary,
+            _ => Format::Compressed,
+        }
+    }
+
+    /// Raises the format of the store in `dir` to `target`, unless it is
+    /// already at least that new, and returns the resulting format.
+    pub(super) fn upgrade(self, dir: &Path, target: Format) -> Result<Format> {
+        if self >= target {
+            return Ok(self);
+        }
+        target.write_marker(dir)?;
+        Ok(target)
+    }
+
+    /// Records `self` as the format of the store in `dir`.
+    ///
+    /// The marker is replaced atomically and synced, since it must be on
+    /// disk before the first record in the new format.
+    pub(super) fn write_marker(self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join("format.tmp");
+        fs::write(&tmp_path, (self as u8).to_string())?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, marker_path(dir))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Returns the format `payload` was written in.
+    pub(super) fn of(payload: &[u8]) -> Format {
+        match payload.first() {
+            Some(&version) if version == Format::Binary as u8 => Format::Binary,
+            Some(&version) if version == Format::Compressed as u8 => Format::Compressed,
+            Some(&version) if version == Format::Encrypted as u8 => Format::Encrypted,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// How new records are encoded.
+#[derive(Debug, Clone)]
+pub(super) struct Encoding {
+    /// Format of the store; only JSON stores still write JSON.
+    pub(super) format: Format,
+    /// Codec binary payloads are compressed with.
+    pub(super) compression: Compression,
+    /// Size below which binary payloads are not compressed.
+    pub(super) compression_min_size: usize,
+    /// Keys payloads are encrypted and decrypted with.
+    pub(super) keys: Arc<Keyring>,
+}
+
+impl Encoding {
+    /// Encodes `cmd` as a record payload.
+    pub(super) fn encode(&self, cmd: &Command) -> Result<Vec<u8>> {
+        if self.format == Format::Json {
+            return Ok(serde_json::to_vec(cmd)?);
+        }
+        let payload = self.compress(encode_binary(cmd))?;
+        if !self.keys.encrypts() {
+            return Ok(payload);
+        }
+        self.keys.seal(Format::Encrypted as u8, &payload)
+    }
+
+    /// Compresses a binary payload if the settings call for it.
+    fn compress(&self, payload: Vec<u8>) -> Result<Vec<u8>> {
+        let codec = match self.compression {
+            Compression::None => return Ok(payload),
+            _ if payload.len() < self.compression_min_size => return Ok(payload),
+            Compression::Lz4 => LZ4,
+            Compression::Zstd => ZSTD,
+        };
+        let mut compressed = vec![Format::Compressed as u8, codec];
+        match codec {
+            LZ4 => compressed.extend(lz4_flex::compress_prepend_size(&payload[1..])),
+            _ => compressed.extend(zstd::bulk::compress(&payload[1..], ZSTD_LEVEL)?),
+        }
+        // Data that does not shrink is better left alone.
+        if compressed.len() >= payload.len() {
+            return Ok(payload);
+        }
+        Ok(compressed)
+    }
+
+    /// Whether `payload` is encoded as `self` would encode it, so that
+    /// compaction can copy it unchanged.
+    pub(super) fn is_current(&self, payload: &[u8]) -> bool {
+        match Format::of(payload) {
+            Format::Encrypted => {
+                self.keys.is_current(payload)
+                    && self
+                        .keys
+                        .open(payload)
+                        .is_some_and(|inner| self.is_compressed_as_current(&inner))
+            }
+            _ if self.keys.encrypts() => false,
+            _ => self.is_compressed_as_current(payload),
+        }
+    }
+
+    /// Whether an unencrypted `payload` is compressed as `self` would
+    /// compress it.
+    fn is_compressed_as_current(&self, payload: &[u8]) -> bool {
+        match (Format::of(payload), self.compression) {
+            (Format::Json | Format::Encrypted, _) => false,
+            (Format::Binary, Compression::None) => true,
+            (Format::Binary, _) => payload.len() < self.compression_min_size,
+            (Format::Compressed, Compression::None) => false,
+            (Format::Compressed, Compression::Lz4) => payload.get(1) == Some(&LZ4),
+            (Format::Compressed, Compression::Zstd) => payload.get(1) == Some(&ZSTD),
+        }
+    }
+}
+
+/// Decodes the payload of the record at `pos` in generation `gen`.
+///
+/// A payload that decodes in no format, or that is encrypted and fails to
+/// authenticate with any of `keys`, is reported as `KvError::Corruption`.
+pub(super) fn decode(payload: &[u8], keys: &Keyring, gen: u64, pos: u64) -> Result<Command> {
+    let cmd = match Format::of(payload) {
+        Format::Encrypted => keys
+            .open(payload)
+            .and_then(|inner| match Format::of(&inner) {
+                Format::Binary | Format::Compressed => decode_unencrypted(&inner),
+                _ => None,
+            }),
+        _ => decode_unencrypted(payload),
+    };
+    cmd.ok_or(KvError::Corruption { gen, pos })
+}
+
+/// Checks that a store in `format` can be read with `keys`.
+pub(super) fn check_keys(format: Format, keys: &Keyring) -> Result<()> {
+    if format == Format::Encrypted && keys.is_empty() {
+        return Err(KvError::StringError(
+            "The store may hold encrypted records; an encryption key is required".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the path of the format marker of the store in `dir`.
+pub(super) fn marker_path(dir: &Path) -> PathBuf {
+    dir.join("format")
+}
+
+/// Decodes a payload that is not encrypted.
+fn decode_unencrypted(payload: &[u8]) -> Option<Command> {
+    match Format::of(payload) {
+        Format::Compressed => decompress(payload).and_then(|body| decode_binary(&body)),
+        Format::Binary => decode_binary(&payload[1..]),
+        Format::Json => serde_json::from_slice(payload).ok(),
+        Format::Encrypted => None,
+    }
+}
+
+/// Decompresses a compressed payload into a binary one without its
+/// version byte.
+fn decompress(payload: &[u8]) -> Option<Vec<u8>> {
+    let (&codec, data) = payload.get(1..)?.split_first()?;
+    match codec {
+        LZ4 => lz4_flex::decompress_size_prepended(data).ok(),
+        ZSTD => zstd::decode_all(data).ok(),
+        _ => None,
+    }
+}
+
+fn encode_binary(cmd: &Command) -> Vec<u8> {
+    let mut buf = vec![Format::Binary as u8];
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            seq,
+        } => {
+            buf.push(SET);
+            put_varint(&mut buf, *seq);
+            // Expiry times are Unix milliseconds, so 0 is free to mean none.
+            put_varint(&mut buf, expires_at.unwrap_or(0));
+            put_bytes(&mut buf, key);
+            put_bytes(&mut buf, value);
+        }
+        Command::Remove { key, seq } => {
+            buf.push(REMOVE);
+            put_varint(&mut buf, *seq);
+            put_bytes(&mut buf, key);
+        }
+        Command::BatchBegin { count } => {
+            buf.push(BATCH_BEGIN);
+            put_varint(&mut buf, *count);
+        }
+        Command::BatchCommit => buf.push(BATCH_COMMIT),
+        Command::NextSeq { seq } => {
+            buf.push(NEXT_SEQ);
+            put_varint(&mut buf, *seq);
+        }
+    }
+    buf
+}
+
+/// Decodes a binary payload without its version byte.
+fn decode_binary(mut buf: &[u8]) -> Option<Command> {
+    let (&kind, rest) = buf.split_first()?;
+    buf = rest;
+    let cmd = match kind {
+        SET => Command::Set {
+            seq: get_varint(&mut buf)?,
+            expires_at: Some(get_varint(&mut buf)?).filter(|&at| at != 0),
+            key: get_bytes(&mut buf)?,
+            value: get_bytes(&mut buf)?,
+        },
+        REMOVE => Command::Remove {
+            seq: get_varint(&mut buf)?,
+            key: get_bytes(&mut buf)?,
+        },
+        BATCH_BEGIN => Command::BatchBegin {
+            count: get_varint(&mut buf)?,
+        },
+        BATCH_COMMIT => Command::BatchCommit,
+        NEXT_SEQ => Command::NextSeq {
+            seq: get_varint(&mut buf)?,
+        },
+        _ => return None,
+    };
+    // Trailing bytes mean the record is not what it claims to be.
+    buf.is_empty().then_some(cmd)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()?;
+        *buf = rest;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn get_bytes(buf: &mut &[u8]) -> Option<Vec<u8>> {
+    let len = usize::try_from(get_varint(buf)?).ok()?;
+    if buf.len() < len {
+        return None;
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(bytes.to_vec())
+}
@@ -0,0 +1,93 @@
+//! Queue of writes waiting for a group commit.
+//!
+//! Writers push their command onto the shared queue and then block on the
+//! writer `Mutex`. Whichever writer acquires it first becomes the leader:
+//! it drains every queued write, appends them to the log, flushes and
+//! syncs once, and fills in each write's result. Writers that acquire the
+//! lock afterwards find their result already set and return immediately.
+
+use std::io;
+use std::mem;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use super::Command;
+use crate::{KvError, Result};
+
+/// A write operation submitted by one caller.
+pub(super) enum WriteOp {
+    /// A single `Set` or `Remove`.
+    Command(Command),
+    /// `Set`s and `Remove`s committed all together or not at all.
+    Batch(Vec<Command>),
+}
+
+impl WriteOp {
+    /// Gives each command of the operation the next sequence number.
+    pub(super) fn assign_seqs(&mut self, next_seq: &mut u64) {
+        let cmds = match self {
+            WriteOp::Command(cmd) => slice::from_mut(cmd),
+            WriteOp::Batch(cmds) => cmds.as_mut_slice(),
+        };
+        for cmd in cmds {
+            cmd.set_seq(*next_seq);
+            *next_seq += 1;
+        }
+    }
+}
+
+/// A write waiting to be committed.
+pub(super) struct PendingWrite {
+    /// The operation to append to the log. Only the leader committing the
+    /// write locks it, to assign its sequence numbers.
+    pub(super) op: Mutex<WriteOp>,
+    /// Outcome of the write, set by the leader that committed it.
+    result: Mutex<Option<Result<()>>>,
+}
+
+impl PendingWrite {
+    pub(super) fn new(op: WriteOp) -> Arc<Self> {
+        Arc::new(Self {
+            op: Mutex::new(op),
+            result: Mutex::new(None),
+        })
+    }
+
+    /// Records the outcome of this write.
+    pub(super) fn complete(&self, result: Result<()>) {
+        *self.result.lock().unwrap() = Some(result);
+    }
+
+    /// Takes the outcome of this write, if a leader has committed it.
+    pub(super) fn take_result(&self) -> Option<Result<()>> {
+        self.result.lock().unwrap().take()
+    }
+}
+
+/// Writes queued for the next leader.
+#[derive(Default)]
+pub(super) struct CommitQueue {
+    pending: Mutex<Vec<Arc<PendingWrite>>>,
+}
+
+impl CommitQueue {
+    /// Adds a write to the queue.
+    pub(super) fn push(&self, write: Arc<PendingWrite>) {
+        self.pending.lock().unwrap().push(write);
+    }
+
+    /// Removes and returns every queued write, oldest first.
+    pub(super) fn drain(&self) -> Vec<Arc<PendingWrite>> {
+        mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+/// Builds an error for one write of a group that failed as a whole.
+///
+/// `KvError` is not `Clone`, so every waiter gets its own copy.
+pub(super) fn group_error(e: &KvError) -> KvError {
+    match e {
+        KvError::Io(io_err) => KvError::Io(io::Error::new(io_err.kind(), io_err.to_string())),
+        other => KvError::StringError(other.to_string()),
+    }
+}
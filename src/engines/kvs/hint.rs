@@ -0,0 +1,117 @@
+//! Hint files for fast startup.
+//!
+//! When compaction writes a generation it also writes `<gen>.hint`, which
+//! lists the key, offset, length, expiry and sequence number of every
+//! record in that generation. `KvStore::open` can rebuild the index for
+//! the generation from the hint alone instead of parsing every record of
+//! the log, as in Bitcask.
+//!
+//! Each entry is stored with the same length + CRC32 framing as the log,
+//! and encrypted like a log record if the store has an encryption key.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::encryption::Keyring;
+use super::format::Format;
+use super::record::{self, Frame};
+use crate::{KvError, Result};
+
+/// Location of one record in a compacted generation.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct HintEntry {
+    #[serde(with = "crate::encoding")]
+    pub(super) key: Vec<u8>,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) expires_at: Option<u64>,
+    #[serde(default)]
+    pub(super) seq: u64,
+}
+
+/// Writes the hint file for generation `gen` and syncs it.
+pub(super) fn write_hint(
+    dir: &Path,
+    gen: u64,
+    entries: &[HintEntry],
+    keys: &Keyring,
+) -> Result<()> {
+    let file = File::create(hint_path(dir, gen))?;
+    let mut writer = BufWriter::new(file);
+    for entry in entries {
+        let mut payload = serde_json::to_vec(entry)?;
+        if keys.encrypts() {
+            payload = keys.seal(Format::Encrypted as u8, &payload)?;
+        }
+        record::write_frame(&mut writer, &payload)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// Reads the hint file for generation `gen`.
+///
+/// Returns `None` if the generation has no hint file. A hint that is
+/// damaged or does not cover exactly `log_len` bytes of the log is an
+/// error, so the caller can fall back to replaying the log. So is an
+/// encrypted hint that none of `keys` opens.
+pub(super) fn read_hint(
+    dir: &Path,
+    gen: u64,
+    log_len: u64,
+    keys: &Keyring,
+) -> Result<Option<Vec<HintEntry>>> {
+    let path = hint_path(dir, gen);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mut reader = BufReader::new(File::open(&path)?);
+    let mut entries = Vec::new();
+    let mut end = 0;
+    loop {
+        match record::read_frame(&mut reader)? {
+            Frame::Record(mut payload) => {
+                if Format::of(&payload) == Format::Encrypted {
+                    payload = keys.open(&payload).ok_or_else(|| {
+                        KvError::StringError(format!("cannot decrypt hint file {}", path.display()))
+                    })?;
+                }
+                let entry: HintEntry = serde_json::from_slice(&payload)?;
+                end = end.max(entry.pos + entry.len);
+                entries.push(entry);
+            }
+            Frame::Eof => break,
+            Frame::Truncated | Frame::Corrupt => {
+                return Err(KvError::StringError(format!(
+                    "damaged hint file {}",
+                    path.display()
+                )))
+            }
+        }
+    }
+    if end != log_len {
+        return Err(KvError::StringError(format!(
+            "hint file {} does not match its log",
+            path.display()
+        )));
+    }
+    Ok(Some(entries))
+}
+
+/// Removes the hint file for generation `gen`, if there is one.
+pub(super) fn remove_hint(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(hint_path(dir, gen)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the path of the hint file for the given generation.
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{gen}.hint"))
+}
@@ -0,0 +1,59 @@
+//! Exclusive ownership of a data directory.
+//!
+//! An open store holds an exclusive lock on a `lock` file in its directory
+//! for as long as any clone of it is alive. Opening the directory again,
+//! from this process or another, fails instead of replaying logs the first
+//! store is still appending to. Offline operations that change the
+//! directory, such as restore and repair, take the same lock.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{KvError, Result};
+
+/// How long to wait for the lock before reporting the directory in use.
+const RELEASE_WAIT: Duration = Duration::from_secs(1);
+
+/// A held lock on a data directory, released on drop.
+#[derive(Debug)]
+pub(super) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks `dir`, failing if a store or another operation holds it.
+    ///
+    /// A holder that lets go within `RELEASE_WAIT`, such as a store whose
+    /// last clones are being dropped on other threads, is waited for.
+    pub(super) fn acquire(dir: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(dir))?;
+        let deadline = Instant::now() + RELEASE_WAIT;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(Self { _file: file }),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(KvError::StringError(format!(
+                        "{} is in use by an open store",
+                        dir.display()
+                    )))
+                }
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Returns the path of the lock file of `dir`.
+pub(super) fn lock_path(dir: &Path) -> PathBuf {
+    dir.join("lock")
+}
@@ -0,0 +1,1447 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{error, warn};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use self::cache::ValueCache;
+use self::compaction::Compactor;
+use self::encryption::Keyring;
+use self::format::{Encoding, Format};
+use self::group_commit::{group_error, CommitQueue, PendingWrite, WriteOp};
+use self::hint::HintEntry;
+use self::lock::DirLock;
+use self::record::Frame;
+use self::snapshot::Pins;
+use super::batch::BatchOp;
+use super::{
+    expiry_after, from_millis, now_millis, BytesScanIter, KvsEngine, Subscription, WriteBatch,
+};
+use crate::{KvError, Result};
+
+pub use self::cache::CacheStats;
+pub use self::encryption::EncryptionKey;
+pub use self::options::{Compression, KvStoreOptions, SyncPolicy};
+pub use self::snapshot::KvStoreSnapshot;
+pub use self::transaction::KvStoreTransaction;
+pub use self::verify::{Damage, GenerationReport, VerifyReport};
+
+mod backup;
+mod cache;
+mod compaction;
+mod encryption;
+mod format;
+mod group_commit;
+mod hint;
+mod lock;
+mod options;
+mod record;
+mod snapshot;
+mod subscribe;
+mod transaction;
+mod verify;
+
+/// Represents a command that can be serialized to the log.
+///
+/// The commands of a `WriteBatch` are written between a `BatchBegin` and a
+/// `BatchCommit` marker, and replay only applies them once the commit
+/// marker has been read.
+///
+/// Every `Set` and `Remove` carries the sequence number it was committed
+/// with. Records written before sequence numbers existed read as 0.
+#[derive(Serialize, Deserialize, Debug)]
+enum Command {
+    Set {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        #[serde(with = "crate::encoding")]
+        value: Vec<u8>,
+        /// Time after which the value is gone, in Unix milliseconds.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        #[serde(default)]
+        seq: u64,
+    },
+    Remove {
+        #[serde(with = "crate::encoding")]
+        key: Vec<u8>,
+        #[serde(default)]
+        seq: u64,
+    },
+    BatchBegin {
+        count: u64,
+    },
+    BatchCommit,
+    /// Sequence number to continue from, written by compaction before it
+    /// deletes records that may hold the highest one handed out so far.
+    NextSeq {
+        seq: u64,
+    },
+}
+
+impl Command {
+    /// Builds a `Set`; its sequence number is assigned on commit.
+    fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            seq: 0,
+        }
+    }
+
+    /// Builds a `Remove`; its sequence number is assigned on commit.
+    fn remove(key: Vec<u8>) -> Command {
+        Command::Remove { key, seq: 0 }
+    }
+
+    /// Sequence number of a `Set` or `Remove`, 0 for other commands.
+    fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. } | Command::Remove { seq, .. } => *seq,
+            _ => 0,
+        }
+    }
+
+    /// Sets the sequence number of a `Set` or `Remove`.
+    fn set_seq(&mut self, new_seq: u64) {
+        if let Command::Set { seq, .. } | Command::Remove { seq, .. } = self {
+            *seq = new_seq;
+        }
+    }
+
+    /// Expiry time of the value written by a `Set`, if it has one.
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires_at, .. } => *expires_at,
+            _ => None,
+        }
+    }
+}
+
+/// In-memory index: key -> position of its latest `Set` in the log,
+/// ordered by key so that ranges can be scanned.
+type Index = BTreeMap<Vec<u8>, CommandPos>;
+
+/// Pointer to a command's position in the log.
+#[derive(Debug, Clone, Copy)]
+struct CommandPos {
+    /// Log file generation number.
+    gen: u64,
+    /// Byte offset of the record (header included) in the file.
+    pos: u64,
+    /// Length of the framed record in bytes.
+    len: u64,
+    /// Expiry time of the value, in Unix milliseconds.
+    expires_at: Option<u64>,
+    /// Sequence number of the write that set the value.
+    seq: u64,
+}
+
+impl CommandPos {
+    /// Whether the value has expired at time `now`.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A log-structured key-value store with lock-free readers.
+///
+/// Write operations are serialized via a `Mutex`. The in-memory index
+/// uses `RwLock` for concurrent read access. Each clone maintains its
+/// own set of file readers to avoid lock contention on reads.
+pub struct KvStore {
+    /// Directory where log files are stored (immutable).
+    path: Arc<PathBuf>,
+    /// Shared in-memory index: key -> log pointer. RwLock allows
+    /// multiple concurrent readers with a single writer.
+    index: Arc<RwLock<Index>>,
+    /// Writer-side state, protected by Mutex (single writer).
+    writer: Arc<Mutex<KvStoreWriter>>,
+    /// Writes waiting for the next group commit.
+    queue: Arc<CommitQueue>,
+    /// Whether concurrent writes are committed together.
+    group_commit: bool,
+    /// Per-clone reader handles (not shared between threads).
+    reader: KvStoreReader,
+    /// Generations pinned by live snapshots.
+    pins: Arc<Pins>,
+    /// Background compaction thread. Declared last so that it is joined
+    /// only after this handle's references to the shared state are gone.
+    compactor: Arc<Compactor>,
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        KvStore {
+            path: self.path.clone(),
+            index: self.index.clone(),
+            writer: self.writer.clone(),
+            queue: self.queue.clone(),
+            group_commit: self.group_commit,
+            // Each clone gets a fresh set of readers — this is the key
+            // to lock-free reads: no shared mutable reader state.
+            reader: self.reader.fresh(),
+            pins: self.pins.clone(),
+            compactor: self.compactor.clone(),
+        }
+    }
+}
+
+/// Writer-side state, protected by a Mutex.
+struct KvStoreWriter {
+    /// Current generation number for the active log file.
+    current_gen: u64,
+    /// `current_gen`, shared with the readers so they know which
+    /// generations are sealed.
+    active_gen: Arc<AtomicU64>,
+    /// Writer for the current active log file.
+    writer: BufWriterWithPos<File>,
+    /// Generations currently on disk, the active one included.
+    gens: BTreeSet<u64>,
+    /// Number of bytes of stale (compactable) data.
+    uncompacted: u64,
+    /// Total size of all generations on disk, stale data included.
+    total: u64,
+    /// Options the store was opened with.
+    options: KvStoreOptions,
+    /// Writes acknowledged since the active log was last synced.
+    unsynced_writes: u64,
+    /// Time of the last sync of the active log.
+    last_sync: Instant,
+    /// Sequence number of the next committed write.
+    next_seq: u64,
+    /// Channels of the live subscriptions.
+    subscribers: Vec<subscribe::Subscriber>,
+    /// How new records are encoded.
+    encoding: Encoding,
+    /// Set when a failed append could not be cut from the active log.
+    /// Every later write fails until the store is reopened.
+    poisoned: bool,
+}
+
+impl KvStoreWriter {
+    /// Flushes `writes` newly appended records to the active log and
+    /// syncs it if the policy requires it.
+    fn commit(&mut self, writes: u64) -> Result<()> {
+        self.writer.flush()?;
+        self.unsynced_writes += writes;
+        let due = match self.options.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryNMillis(ms) => self.last_sync.elapsed() >= Duration::from_millis(ms),
+            SyncPolicy::EveryNWrites(n) => self.unsynced_writes >= n,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Whether enough stale data has accumulated to compact the log.
+    fn needs_compaction(&self) -> bool {
+        let ratio_reached = match self.options.compaction_ratio {
+            Some(ratio) if self.total > 0 => self.uncompacted as f64 / self.total as f64 >= ratio,
+            _ => false,
+        };
+        self.uncompacted > 0
+            && (self.uncompacted > self.options.compaction_threshold || ratio_reached)
+    }
+
+    /// Seals the active log and starts appending to generation `gen`.
+    ///
+    /// Writes still waiting for an interval-based sync are synced first.
+    fn roll(&mut self, path: &Path, gen: u64) -> Result<()> {
+        if self.options.sync_policy != SyncPolicy::Never && self.unsynced_writes > 0 {
+            self.sync()?;
+        }
+        self.writer = new_log_file(path, gen)?;
+        self.current_gen = gen;
+        self.gens.insert(gen);
+        // Everything written to the sealed log has been flushed, so readers
+        // may map it from now on.
+        self.active_gen.store(gen, Ordering::Release);
+        Ok(())
+    }
+
+    /// Syncs the active log if writes have waited for it longer than the
+    /// interval of the sync policy.
+    fn sync_overdue(&mut self) -> Result<()> {
+        match self.options.sync_policy.interval() {
+            Some(interval)
+                if !self.poisoned
+                    && self.unsynced_writes > 0
+                    && self.last_sync.elapsed() >= interval =>
+            {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Forces the active log to stable storage.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.sync_data()?;
+        self.unsynced_writes = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Cuts the active log back to `pos` after appending to it failed, so
+    /// that records of writes reported as failed are not flushed later or
+    /// replayed on the next open. Poisons the writer if that fails too.
+    fn undo_append(&mut self, pos: u64) {
+        if let Err(e) = self.writer.truncate(pos) {
+            error!(
+                "Failed to discard a failed write from generation {}: {}; \
+                 refusing writes until the store is reopened",
+                self.current_gen, e
+            );
+            self.poisoned = true;
+        }
+    }
+
+    /// Fails if an earlier failed append could not be undone.
+    fn check_poisoned(&self) -> Result<()> {
+        if self.poisoned {
+            return Err(KvError::StringError(
+                "The log could not be repaired after a failed write; reopen the store".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    /// Syncs writes still pending under an interval-based policy.
+    fn drop(&mut self) {
+        // A poisoned writer may still buffer records of failed writes.
+        if self.poisoned {
+            return;
+        }
+        if self.options.sync_policy != SyncPolicy::Never && self.unsynced_writes > 0 {
+            if let Err(e) = self.sync() {
+                warn!("Failed to sync log on close: {}", e);
+            }
+        }
+    }
+}
+
+/// Per-clone reader state. Each thread gets its own instance via Clone.
+///
+/// Sealed generations are memory-mapped, since they do not change until
+/// compaction deletes them; only the active one is read through a buffer.
+struct KvStoreReader {
+    /// Minimum valid generation after compaction.
+    safe_point: Arc<AtomicU64>,
+    /// Generation of the active log; older ones are sealed.
+    active_gen: Arc<AtomicU64>,
+    /// Path to log directory (for lazy file opening).
+    path: Arc<PathBuf>,
+    /// Buffer capacity of each reader handle.
+    buffer_size: usize,
+    /// Keys encrypted records are decrypted with.
+    keys: Arc<Keyring>,
+    /// Values recently read, shared by every clone.
+    cache: Arc<ValueCache>,
+    /// Per-thread reader handles, lazily opened.
+    readers: RefCell<HashMap<u64, BufReaderWithPos<File>>>,
+    /// Per-thread mappings of sealed generations, lazily created.
+    maps: RefCell<HashMap<u64, Mmap>>,
+}
+
+impl KvStoreReader {
+    /// Returns a reader of the same logs with no open handles.
+    fn fresh(&self) -> KvStoreReader {
+        KvStoreReader {
+            safe_point: self.safe_point.clone(),
+            active_gen: self.active_gen.clone(),
+            path: self.path.clone(),
+            buffer_size: self.buffer_size,
+            keys: self.keys.clone(),
+            cache: self.cache.clone(),
+            readers: RefCell::new(HashMap::new()),
+            maps: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Reads a command from the log using per-thread file handles.
+    ///
+    /// Serves the value from the cache if it holds it. Otherwise lazily
+    /// opens file handles or mappings as needed, and cleans up stale ones
+    /// when the safe_point advances (after compaction).
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.cache.get(cmd_pos) {
+            return Ok(Some(value));
+        }
+        self.close_stale_readers();
+
+        let cmd = if cmd_pos.gen < self.active_gen.load(Ordering::Acquire) {
+            self.read_sealed(cmd_pos)?
+        } else {
+            self.read_active(cmd_pos)?
+        };
+        match cmd {
+            Some(Command::Set { value, .. }) => {
+                self.cache.insert(cmd_pos, &value);
+                Ok(Some(value))
+            }
+            Some(_) => Err(KvError::UnexpectedCommandType),
+            None => Err(KvError::Corruption {
+                gen: cmd_pos.gen,
+                pos: cmd_pos.pos,
+            }),
+        }
+    }
+
+    /// Reads the record at `cmd_pos` from a mapping of its sealed
+    /// generation.
+    fn read_sealed(&self, cmd_pos: CommandPos) -> Result<Option<Command>> {
+        let mut maps = self.maps.borrow_mut();
+        let map = match maps.entry(cmd_pos.gen) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                // The handle used while the generation was active is no
+                // longer needed.
+                self.readers.borrow_mut().remove(&cmd_pos.gen);
+                let file = snapshot::open_log(&self.path, cmd_pos.gen)?;
+                // SAFETY: a sealed log is never written again. Compaction
+                // only unlinks or renames it, which leaves the mapping
+                // intact, and the logs are only truncated while no store
+                // has them open.
+                e.insert(unsafe { Mmap::map(&file)? })
+            }
+        };
+        let corruption = || KvError::Corruption {
+            gen: cmd_pos.gen,
+            pos: cmd_pos.pos,
+        };
+        let start = usize::try_from(cmd_pos.pos).map_err(|_| corruption())?;
+        let len = usize::try_from(cmd_pos.len).map_err(|_| corruption())?;
+        let mut record = start
+            .checked_add(len)
+            .and_then(|end| map.get(start..end))
+            .ok_or_else(corruption)?;
+        read_record(&mut record, &self.keys, cmd_pos.gen, cmd_pos.pos)
+    }
+
+    /// Reads the record at `cmd_pos` through a buffered handle, as the
+    /// active generation may still grow.
+    fn read_active(&self, cmd_pos: CommandPos) -> Result<Option<Command>> {
+        let mut readers = self.readers.borrow_mut();
+        let reader = match readers.entry(cmd_pos.gen) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let r = BufReaderWithPos::with_capacity(
+                    self.buffer_size,
+                    snapshot::open_log(&self.path, cmd_pos.gen)?,
+                )?;
+                e.insert(r)
+            }
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut cmd_reader = reader.take(cmd_pos.len);
+        read_record(&mut cmd_reader, &self.keys, cmd_pos.gen, cmd_pos.pos)
+    }
+
+    /// Removes file handles and mappings for generations older than the
+    /// safe point.
+    fn close_stale_readers(&self) {
+        let safe_point = self.safe_point.load(Ordering::Acquire);
+        if safe_point > 0 {
+            let mut readers = self.readers.borrow_mut();
+            readers.retain(|&gen, _| gen >= safe_point);
+            let mut maps = self.maps.borrow_mut();
+            maps.retain(|&gen, _| gen >= safe_point);
+        }
+    }
+}
+
+impl KvStore {
+    /// Opens a `KvStore` at the given path.
+    ///
+    /// Creates the directory if it does not exist.
+    /// Replays existing log files to rebuild the in-memory index.
+    /// Fails if the directory is already held by an open store, in this
+    /// process or another.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` at the given path with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        Ok(Self::open_with_report(path, options)?.0)
+    }
+
+    /// Opens a `KvStore` with the given options and reports any recovery
+    /// performed on the way.
+    ///
+    /// Generations written by compaction are indexed from their `.hint`
+    /// file when it is present and intact, and replayed otherwise.
+    ///
+    /// If the newest generation ends in a partially written record (for
+    /// example because the process died in the middle of a `set`), the
+    /// incomplete tail is truncated and the store opens normally. A write
+    /// batch cut off this way is discarded as a whole. Damage anywhere else
+    /// is still reported as `KvError::Corruption`.
+    pub fn open_with_report(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<(Self, RecoveryReport)> {
+        let path = path.into();
+        options.validate()?;
+        fs::create_dir_all(&path)?;
+        let lock = DirLock::acquire(&path)?;
+        Self::open_inner(path, options, lock)
+    }
+
+    /// Replays the logs under `path`, which `lock` holds, and builds the
+    /// store.
+    fn open_inner(
+        path: PathBuf,
+        options: KvStoreOptions,
+        lock: DirLock,
+    ) -> Result<(Self, RecoveryReport)> {
+        snapshot::remove_retired(&path)?;
+
+        let mut index = Index::new();
+        let mut uncompacted = 0u64;
+        let mut total = 0u64;
+        let mut next_seq = 1u64;
+        let mut report = RecoveryReport::default();
+
+        let keys = Arc::new(options.keyring());
+        let gen_list = sorted_gen_list(&path)?;
+        let format = Format::open(
+            &path,
+            !gen_list.is_empty(),
+            Format::writing(options.compression, keys.encrypts()),
+            &keys,
+        )?;
+        for (i, &gen) in gen_list.iter().enumerate() {
+            let mut reader = BufReaderWithPos::with_capacity(
+                options.read_buffer_size,
+                File::open(log_path(&path, gen))?,
+            )?;
+            let log_len = reader.seek(SeekFrom::End(0))?;
+            total += log_len;
+            match hint::read_hint(&path, gen, log_len, &keys) {
+                Ok(Some(entries)) => {
+                    for HintEntry {
+                        key,
+                        pos,
+                        len,
+                        expires_at,
+                        seq,
+                    } in entries
+                    {
+                        next_seq = next_seq.max(seq + 1);
+                        let cmd_pos = CommandPos {
+                            gen,
+                            pos,
+                            len,
+                            expires_at,
+                            seq,
+                        };
+                        if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                            uncompacted += old_cmd.len;
+                        }
+                    }
+                    report.hints_loaded += 1;
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "Replaying generation {} instead of using its hint: {}",
+                    gen, e
+                ),
+            }
+
+            let is_newest = i + 1 == gen_list.len();
+            let loaded = load(gen, &mut reader, &mut index, &keys, is_newest)?;
+            uncompacted += loaded.uncompacted;
+            next_seq = next_seq.max(loaded.next_seq);
+            if let Some(tail) = loaded.torn_tail {
+                let discarded = truncate_log(&path, gen, tail)?;
+                total -= discarded;
+                warn!(
+                    "Discarded {} bytes of incomplete record at the end of {}",
+                    discarded,
+                    log_path(&path, gen).display()
+                );
+                report.truncated_gen = Some(gen);
+                report.discarded_bytes = discarded;
+            }
+        }
+        report.generations_loaded = gen_list.len();
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = new_log_file(&path, current_gen)?;
+        let mut gens: BTreeSet<u64> = gen_list.into_iter().collect();
+        gens.insert(current_gen);
+
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let path = Arc::new(path);
+
+        let active_gen = Arc::new(AtomicU64::new(current_gen));
+        let kv_writer = KvStoreWriter {
+            current_gen,
+            active_gen: active_gen.clone(),
+            writer,
+            gens,
+            uncompacted,
+            total,
+            options: options.clone(),
+            unsynced_writes: 0,
+            last_sync: Instant::now(),
+            next_seq,
+            subscribers: Vec::new(),
+            encoding: Encoding {
+                format,
+                compression: options.compression,
+                compression_min_size: options.compression_min_size,
+                keys: keys.clone(),
+            },
+            poisoned: false,
+        };
+
+        let reader = KvStoreReader {
+            safe_point: safe_point.clone(),
+            active_gen,
+            path: path.clone(),
+            buffer_size: options.read_buffer_size,
+            keys,
+            cache: Arc::new(ValueCache::new(options.cache_size)),
+            readers: RefCell::new(HashMap::new()),
+            maps: RefCell::new(HashMap::new()),
+        };
+
+        let index = Arc::new(RwLock::new(index));
+        let writer = Arc::new(Mutex::new(kv_writer));
+        let pins = Arc::new(Pins::new(path.clone(), lock));
+        let compactor = Compactor::spawn(
+            path.clone(),
+            Arc::downgrade(&index),
+            Arc::downgrade(&writer),
+            safe_point,
+            pins.clone(),
+            reader.cache.clone(),
+            options.sync_policy.interval(),
+        )?;
+
+        let store = Self {
+            path,
+            index,
+            writer,
+            queue: Arc::new(CommitQueue::default()),
+            group_commit: options.group_commit,
+            reader,
+            pins,
+            compactor: Arc::new(compactor),
+        };
+        Ok((store, report))
+    }
+}
+
+/// Summary of the recovery performed by [`KvStore::open_with_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Number of log generations loaded.
+    pub generations_loaded: usize,
+    /// Number of those generations whose index came from a hint file.
+    pub hints_loaded: usize,
+    /// Generation whose incomplete tail was truncated, if any.
+    pub truncated_gen: Option<u64>,
+    /// Number of bytes discarded from the end of `truncated_gen`.
+    pub discarded_bytes: u64,
+}
+
+impl KvStore {
+    /// Queues `op` for a group commit and waits until it is committed.
+    ///
+    /// The first writer to take the writer `Mutex` commits every write
+    /// queued so far with a single flush (and sync); writers whose write
+    /// was committed by an earlier leader return without touching the log.
+    fn submit(&self, op: WriteOp) -> Result<()> {
+        let pending = PendingWrite::new(op);
+        let group_commit = self.group_commit;
+        if group_commit {
+            self.queue.push(pending.clone());
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(result) = pending.take_result() {
+            return result;
+        }
+        let group = if group_commit {
+            self.queue.drain()
+        } else {
+            vec![pending.clone()]
+        };
+        self.lead(&mut writer, &group);
+
+        pending
+            .take_result()
+            .expect("leader must commit its own write")
+    }
+
+    /// Commits `group` as the leader, then rolls the active log or starts
+    /// a compaction if the new records call for it.
+    ///
+    /// The writes of the group have their results once it is committed, so
+    /// a failure to roll is only logged; the next group tries again.
+    fn lead(&self, writer: &mut KvStoreWriter, group: &[Arc<PendingWrite>]) {
+        commit_group(writer, &self.index, group);
+
+        if let Some(max_file_size) = writer.options.max_file_size {
+            if writer.writer.pos >= max_file_size {
+                let next_gen = writer.current_gen + 1;
+                if let Err(e) = writer.roll(&self.path, next_gen) {
+                    error!(
+                        "Failed to roll generation {} to a new log: {}",
+                        writer.current_gen, e
+                    );
+                }
+            }
+        }
+        if writer.needs_compaction() {
+            self.compactor.trigger();
+        }
+    }
+
+    /// Runs `f` while holding the writer `Mutex`, so that no other write
+    /// can land while it checks the index and commits its own write.
+    fn exclusive<T>(&self, f: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        let mut writer = self.writer.lock().unwrap();
+        // Writes queued before this point are committed first, so that `f`
+        // sees them.
+        let group = self.queue.drain();
+        if !group.is_empty() {
+            self.lead(&mut writer, &group);
+        }
+        f(&mut writer)
+    }
+
+    /// Commits `op` on its own, for a caller inside `exclusive`.
+    fn commit_own(&self, writer: &mut KvStoreWriter, op: WriteOp) -> Result<()> {
+        let pending = PendingWrite::new(op);
+        self.lead(writer, slice::from_ref(&pending));
+        pending
+            .take_result()
+            .expect("leader must commit its own write")
+    }
+
+    /// Compares and swaps the value of `key` with no other write landing
+    /// between the two steps.
+    fn swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        self.exclusive(|writer| {
+            if self.lookup(&key)? != expected {
+                return Ok(false);
+            }
+            let cmd = match new {
+                Some(value) => Command::set(key, value, None),
+                None if expected.is_some() => Command::remove(key),
+                // Expected absent and stays absent.
+                None => return Ok(true),
+            };
+            self.commit_own(writer, WriteOp::Command(cmd))
+                .map(|()| true)
+        })
+    }
+
+    /// Commits the writes of a transaction if every key it read still has
+    /// the sequence number it was read at (`None` for an absent key).
+    fn commit_transaction(
+        &self,
+        reads: &BTreeMap<Vec<u8>, Option<u64>>,
+        cmds: Vec<Command>,
+    ) -> Result<()> {
+        self.exclusive(|writer| {
+            {
+                let index = self.index.read().unwrap();
+                let now = now_millis();
+                let changed = reads
+                    .iter()
+                    .any(|(key, &seq)| current_seq(&index, key, now) != seq);
+                if changed {
+                    return Err(KvError::Conflict);
+                }
+            }
+            if cmds.is_empty() {
+                return Ok(());
+            }
+            self.commit_own(writer, WriteOp::Batch(cmds))
+        })
+    }
+}
+
+impl KvStore {
+    /// Looks `key` up in the index and reads its value.
+    fn lookup(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup_versioned(key)?.map(|(value, _)| value))
+    }
+
+    /// Looks `key` up in the index and reads its value along with the
+    /// sequence number of the write that set it.
+    fn lookup_versioned(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        // Read-lock the index — multiple threads can do this concurrently.
+        let index = self.index.read().unwrap();
+        let Some(cmd_pos) = index.get(key).copied() else {
+            return Ok(None);
+        };
+        if cmd_pos.is_expired(now_millis()) {
+            return Ok(None);
+        }
+        drop(index); // Release read lock as early as possible.
+        match self.reader.read_command(cmd_pos) {
+            Err(KvError::Io(e)) if self.compacted_away(&e, cmd_pos) => self.lookup_versioned(key),
+            result => Ok(result?.map(|value| (value, cmd_pos.seq))),
+        }
+    }
+
+    /// Reads the value of `key` from the record at `cmd_pos`.
+    fn read_value(&self, key: &[u8], cmd_pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        // Use per-thread reader (lazy open, no shared state).
+        match self.reader.read_command(cmd_pos) {
+            Err(KvError::Io(e)) if self.compacted_away(&e, cmd_pos) => self.lookup(key),
+            result => result,
+        }
+    }
+
+    /// Whether reading the record at `cmd_pos` failed with `e` because
+    /// compaction deleted its generation after the key was looked up. The
+    /// index then points at the copy, so the key should be looked up again.
+    fn compacted_away(&self, e: &io::Error, cmd_pos: CommandPos) -> bool {
+        e.kind() == io::ErrorKind::NotFound
+            && cmd_pos.gen < self.reader.safe_point.load(Ordering::Acquire)
+    }
+
+    /// Starts a scan over the index entries selected by `select`.
+    ///
+    /// The matching keys and positions are collected under the index read
+    /// lock; values are then read lazily as the iterator advances, through
+    /// a clone of the store with its own file handles.
+    fn scan_index<F>(&self, select: F) -> BytesScanIter
+    where
+        F: FnOnce(&Index) -> Vec<(Vec<u8>, CommandPos)>,
+    {
+        let mut entries = select(&self.index.read().unwrap());
+        let now = now_millis();
+        entries.retain(|(_, cmd_pos)| !cmd_pos.is_expired(now));
+        Box::new(KvStoreScan {
+            store: self.clone(),
+            entries: entries.into_iter(),
+        })
+    }
+}
+
+/// Iterator returned by `KvStore::scan_bytes` and
+/// `KvStore::scan_prefix_bytes`.
+struct KvStoreScan {
+    store: KvStore,
+    entries: std::vec::IntoIter<(Vec<u8>, CommandPos)>,
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (key, cmd_pos) in self.entries.by_ref() {
+            match self.store.read_value(&key, cmd_pos) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                // Removed since the scan started.
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+/// Appends a group of queued writes to the active log and commits them.
+///
+/// The writes are flushed and synced once, then published to the index
+/// together. An I/O error fails every write of the group, and its records
+/// are cut from the log again.
+fn commit_group(writer: &mut KvStoreWriter, index: &RwLock<Index>, group: &[Arc<PendingWrite>]) {
+    if let Err(e) = writer.check_poisoned() {
+        for pending in group {
+            pending.complete(Err(group_error(&e)));
+        }
+        return;
+    }
+    // Sequence numbers are handed out in log order. Removes that turn out
+    // to fail leave a gap.
+    let ops: Vec<MutexGuard<WriteOp>> = group
+        .iter()
+        .map(|pending| {
+            let mut op = pending.op.lock().unwrap();
+            op.assign_seqs(&mut writer.next_seq);
+            op
+        })
+        .collect();
+    let start = writer.writer.pos;
+    let appended = match append_group(writer, index, group, &ops) {
+        Ok(appended) => appended,
+        Err(e) => {
+            writer.undo_append(start);
+            for pending in group {
+                pending.complete(Err(group_error(&e)));
+            }
+            return;
+        }
+    };
+
+    {
+        let mut index = index.write().unwrap();
+        writer.total += appended.markers;
+        writer.uncompacted += appended.markers;
+        for (cmd, cmd_pos) in &appended.commands {
+            writer.total += cmd_pos.len;
+            writer.uncompacted += apply_command(&mut index, cmd, *cmd_pos);
+        }
+    }
+    subscribe::publish(
+        &mut writer.subscribers,
+        appended.commands.iter().map(|(cmd, _)| *cmd),
+    );
+    for pending in appended.writes {
+        pending.complete(Ok(()));
+    }
+}
+
+/// Records appended to the log by `append_group`.
+struct Appended<'a> {
+    /// Writes whose records were appended.
+    writes: Vec<&'a PendingWrite>,
+    /// Every `Set` and `Remove` appended, with its position, in log order.
+    commands: Vec<(&'a Command, CommandPos)>,
+    /// Bytes of batch markers appended, which are stale from the start.
+    markers: u64,
+}
+
+/// Writes the commands of `group` to the active log and commits the file.
+///
+/// Removes of keys that do not exist, taking earlier writes of the same
+/// group into account, fail individually with `KvError::KeyNotFound` and
+/// are left out; inside a `WriteBatch` they are skipped instead.
+fn append_group<'a>(
+    writer: &mut KvStoreWriter,
+    index: &RwLock<Index>,
+    group: &'a [Arc<PendingWrite>],
+    ops: &'a [MutexGuard<WriteOp>],
+) -> Result<Appended<'a>> {
+    let mut appended = Appended {
+        writes: Vec::with_capacity(group.len()),
+        commands: Vec::with_capacity(group.len()),
+        markers: 0,
+    };
+    // Whether each key touched by the group exists after its earlier writes.
+    let mut exists: HashMap<&[u8], bool> = HashMap::new();
+    let now = now_millis();
+    {
+        let index = index.read().unwrap();
+        for (pending, op) in group.iter().zip(ops) {
+            match &**op {
+                WriteOp::Command(cmd) => {
+                    if !track_key(&mut exists, &index, now, cmd) {
+                        pending.complete(Err(KvError::KeyNotFound));
+                        continue;
+                    }
+                    let cmd_pos = append_command(writer, cmd)?;
+                    appended.commands.push((cmd, cmd_pos));
+                }
+                WriteOp::Batch(cmds) => {
+                    let cmds: Vec<&Command> = cmds
+                        .iter()
+                        .filter(|cmd| track_key(&mut exists, &index, now, cmd))
+                        .collect();
+                    if !cmds.is_empty() {
+                        let begin = Command::BatchBegin {
+                            count: cmds.len() as u64,
+                        };
+                        appended.markers += append_command(writer, &begin)?.len;
+                        for cmd in cmds {
+                            let cmd_pos = append_command(writer, cmd)?;
+                            appended.commands.push((cmd, cmd_pos));
+                        }
+                        appended.markers += append_command(writer, &Command::BatchCommit)?.len;
+                    }
+                }
+            }
+            appended.writes.push(pending);
+        }
+    }
+    writer.commit(appended.writes.len() as u64)?;
+    Ok(appended)
+}
+
+/// Records the effect of `cmd` on the keys written so far by a group.
+///
+/// Returns `false` for a `Remove` of a key that does not exist or has
+/// expired at time `now`.
+fn track_key<'a>(
+    exists: &mut HashMap<&'a [u8], bool>,
+    index: &Index,
+    now: u64,
+    cmd: &'a Command,
+) -> bool {
+    match cmd {
+        Command::Set { key, .. } => {
+            exists.insert(key, true);
+            true
+        }
+        Command::Remove { key, .. } => {
+            let present = exists
+                .get(key.as_slice())
+                .copied()
+                .unwrap_or_else(|| index.get(key).is_some_and(|p| !p.is_expired(now)));
+            exists.insert(key, false);
+            present
+        }
+        Command::BatchBegin { .. } | Command::BatchCommit | Command::NextSeq { .. } => true,
+    }
+}
+
+/// Sequence number of the live value of `key` at time `now`, or `None` if
+/// it is absent.
+fn current_seq(index: &Index, key: &[u8], now: u64) -> Option<u64> {
+    index
+        .get(key)
+        .filter(|cmd_pos| !cmd_pos.is_expired(now))
+        .map(|cmd_pos| cmd_pos.seq)
+}
+
+/// Appends `cmd` to the active log and returns its position.
+fn append_command(writer: &mut KvStoreWriter, cmd: &Command) -> Result<CommandPos> {
+    let pos = writer.writer.pos;
+    let len = write_command(&mut writer.writer, cmd, &writer.encoding)?;
+    Ok(CommandPos {
+        gen: writer.current_gen,
+        pos,
+        len,
+        expires_at: cmd.expires_at(),
+        seq: cmd.seq(),
+    })
+}
+
+/// Applies the command at `cmd_pos` to the index.
+///
+/// Returns the number of bytes of the log made stale by it.
+fn apply_command(index: &mut Index, cmd: &Command, cmd_pos: CommandPos) -> u64 {
+    match cmd {
+        Command::Set { key, .. } => index
+            .insert(key.clone(), cmd_pos)
+            .map_or(0, |old_cmd| old_cmd.len),
+        Command::Remove { key, .. } => {
+            index.remove(key).map_or(0, |old_cmd| old_cmd.len) + cmd_pos.len
+        }
+        // Markers carry no data once they have been read.
+        Command::BatchBegin { .. } | Command::BatchCommit | Command::NextSeq { .. } => cmd_pos.len,
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+    type Transaction = KvStoreTransaction;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.submit(WriteOp::Command(Command::set(key, value, None)))
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.submit(WriteOp::Command(Command::set(
+            key,
+            value,
+            Some(expiry_after(ttl)),
+        )))
+    }
+
+    /// Lock-free read: only acquires a RwLock read lock on the index,
+    /// then uses per-thread file handles. No Mutex contention.
+    #[allow(clippy::needless_pass_by_value)]
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.lookup(&key)
+    }
+
+    fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        self.lookup_versioned(&key)
+    }
+
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<SystemTime>> {
+        let index = self.index.read().unwrap();
+        Ok(index
+            .get(&key)
+            .and_then(|cmd_pos| cmd_pos.expires_at)
+            .map(from_millis))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesScanIter> {
+        Ok(self.scan_index(|index| {
+            index
+                .range(range)
+                .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+                .collect()
+        }))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesScanIter> {
+        Ok(self.scan_index(|index| {
+            index
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+                .collect()
+        }))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        // The existence check happens in the committing leader, under the
+        // writer mutex, so a concurrent remove of the same key cannot race
+        // between our check and our write.
+        self.submit(WriteOp::Command(Command::remove(key)))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let cmds = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set {
+                    key,
+                    value,
+                    expires_at,
+                } => Command::set(key, value, expires_at),
+                BatchOp::Remove { key } => Command::remove(key),
+            })
+            .collect();
+        self.submit(WriteOp::Batch(cmds))
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.swap(key, expected, new)
+    }
+
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        // Pinning under the index lock keeps compaction from retiring the
+        // generations the copied index refers to before they are pinned.
+        let index = self.index.read().unwrap();
+        let reader = KvStoreReader {
+            // The snapshot reads generations compaction has moved past, so
+            // its handles must not be closed as stale.
+            safe_point: Arc::new(AtomicU64::new(0)),
+            ..self.reader.fresh()
+        };
+        Ok(KvStoreSnapshot::new(
+            &index,
+            now_millis(),
+            self.pins.clone(),
+            reader,
+        ))
+    }
+
+    fn begin(&self) -> Result<KvStoreTransaction> {
+        Ok(KvStoreTransaction::new(self.clone()))
+    }
+
+    fn subscribe(&self, from_seq: u64) -> Result<Subscription> {
+        let writer = self.writer.lock().unwrap();
+        subscribe::subscribe(&self.path, &self.pins, writer, from_seq)
+    }
+}
+
+/// Returns sorted list of generation numbers from log files in the directory.
+fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter_map(|path| log_gen(&path))
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Returns the generation of `path` if it is a log file.
+///
+/// Only `<gen>.log` is a log: other names that parse as the same number,
+/// such as `007.log`, are not what `log_path` opens.
+fn log_gen(path: &Path) -> Option<u64> {
+    if !path.is_file() || path.extension() != Some("log".as_ref()) {
+        return None;
+    }
+    let stem = path.file_stem().and_then(OsStr::to_str)?;
+    stem.parse()
+        .ok()
+        .filter(|gen: &u64| gen.to_string() == stem)
+}
+
+/// Outcome of replaying a single log file.
+struct Loaded {
+    /// Number of bytes of stale data found in the file.
+    uncompacted: u64,
+    /// Offset of an incomplete trailing record, if one was found.
+    torn_tail: Option<u64>,
+    /// Sequence number following the highest one in the file.
+    next_seq: u64,
+}
+
+/// A write batch being replayed whose commit marker has not been read yet.
+struct OpenBatch {
+    /// Offset of the batch's begin marker.
+    start: u64,
+    /// Length of the begin marker.
+    begin_len: u64,
+    /// Number of commands the begin marker announced.
+    count: u64,
+    /// Commands read so far, with their position.
+    commands: Vec<(Command, CommandPos)>,
+}
+
+/// Loads a single log file and populates the index.
+///
+/// Fails with `KvError::Corruption` at the first record that does not
+/// decode as a command with `keys`, or that is truncated or fails its
+/// checksum. With `allow_torn_tail`, set for the active generation, a
+/// truncated or failing record is instead taken as the start of a torn
+/// write: replay stops there and its offset is returned in
+/// `Loaded::torn_tail`. Nothing after it is trusted, since a partly written
+/// payload may itself hold bytes that look like valid records.
+///
+/// A write batch is applied once its commit marker is read. A batch cut off
+/// by the end of the file counts as a torn tail starting at its begin
+/// marker, so none of it is applied.
+fn load(
+    gen: u64,
+    reader: &mut BufReaderWithPos<File>,
+    index: &mut Index,
+    keys: &Keyring,
+    allow_torn_tail: bool,
+) -> Result<Loaded> {
+    let mut pos = reader.seek(SeekFrom::Start(0))?;
+    let mut uncompacted = 0u64;
+    let mut next_seq = 0u64;
+    let mut batch: Option<OpenBatch> = None;
+
+    loop {
+        let cmd: Command = match record::read_frame(reader)? {
+            Frame::Record(payload) => format::decode(&payload, keys, gen, pos)?,
+            Frame::Eof => break,
+            Frame::Truncated | Frame::Corrupt if allow_torn_tail => {
+                return Ok(Loaded {
+                    uncompacted,
+                    torn_tail: Some(batch.map_or(pos, |batch| batch.start)),
+                    next_seq,
+                })
+            }
+            Frame::Truncated | Frame::Corrupt => return Err(KvError::Corruption { gen, pos }),
+        };
+        let new_pos = reader.pos;
+        let cmd_pos = CommandPos {
+            gen,
+            pos,
+            len: new_pos - pos,
+            expires_at: cmd.expires_at(),
+            seq: cmd.seq(),
+        };
+        next_seq = match cmd {
+            Command::NextSeq { seq } => next_seq.max(seq),
+            _ => next_seq.max(cmd_pos.seq + 1),
+        };
+        match cmd {
+            Command::BatchBegin { count } if batch.is_none() => {
+                batch = Some(OpenBatch {
+                    start: pos,
+                    begin_len: cmd_pos.len,
+                    count,
+                    commands: Vec::new(),
+                });
+            }
+            Command::BatchCommit => match batch.take() {
+                Some(open) if open.commands.len() as u64 == open.count => {
+                    for (cmd, cmd_pos) in &open.commands {
+                        uncompacted += apply_command(index, cmd, *cmd_pos);
+                    }
+                    uncompacted += open.begin_len + cmd_pos.len;
+                }
+                _ => return Err(KvError::Corruption { gen, pos }),
+            },
+            Command::BatchBegin { .. } => return Err(KvError::Corruption { gen, pos }),
+            cmd => match &mut batch {
+                Some(open) => open.commands.push((cmd, cmd_pos)),
+                None => uncompacted += apply_command(index, &cmd, cmd_pos),
+            },
+        }
+        pos = new_pos;
+    }
+
+    match batch {
+        Some(open) if allow_torn_tail => Ok(Loaded {
+            uncompacted,
+            torn_tail: Some(open.start),
+            next_seq,
+        }),
+        Some(open) => Err(KvError::Corruption {
+            gen,
+            pos: open.start,
+        }),
+        None => Ok(Loaded {
+            uncompacted,
+            torn_tail: None,
+            next_seq,
+        }),
+    }
+}
+
+/// Truncates log `gen` to `len` bytes, returning how many were discarded.
+fn truncate_log(path: &Path, gen: u64, len: u64) -> Result<u64> {
+    let file = OpenOptions::new().write(true).open(log_path(path, gen))?;
+    let discarded = file.metadata()?.len() - len;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(discarded)
+}
+
+/// Encodes `cmd` with `encoding` and appends it to `writer` as a framed
+/// record.
+fn write_command<W: Write>(writer: &mut W, cmd: &Command, encoding: &Encoding) -> Result<u64> {
+    let payload = encoding.encode(cmd)?;
+    Ok(record::write_frame(writer, &payload)?)
+}
+
+/// Reads the framed command starting at `pos` in generation `gen`.
+///
+/// Returns `None` at a clean end of log. A truncated record, a checksum
+/// mismatch or a payload that does not decode with `keys` is reported as
+/// `KvError::Corruption`.
+fn read_record<R: Read>(
+    reader: &mut R,
+    keys: &Keyring,
+    gen: u64,
+    pos: u64,
+) -> Result<Option<Command>> {
+    match record::read_frame(reader)? {
+        Frame::Record(payload) => Ok(Some(format::decode(&payload, keys, gen, pos)?)),
+        Frame::Eof => Ok(None),
+        Frame::Truncated | Frame::Corrupt => Err(KvError::Corruption { gen, pos }),
+    }
+}
+
+/// Creates (or reopens for appending) the log file of a generation.
+fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let writer = BufWriterWithPos::new(
+        OpenOptions::new().create(true).append(true).open(&path)?,
+    )?;
+    Ok(writer)
+}
+
+/// Returns the path for a log file with the given generation number.
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{gen}.log"))
+}
+
+/// A `BufReader` that tracks the current read position.
+struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn new(inner: R) -> Result<Self> {
+        Self::with_capacity(8 * 1024, inner)
+    }
+
+    fn with_capacity(capacity: usize, mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(Self {
+            reader: BufReader::with_capacity(capacity, inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// A `BufWriter` that tracks the current write position.
+struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Flushes buffered data and syncs the file contents to disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+
+    /// Discards buffered data and cuts the file back to `len` bytes.
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        // `into_parts` hands back the buffer instead of writing it out.
+        let (old, _discarded) = mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        drop(old);
+        let file = self.writer.get_ref();
+        file.set_len(len)?;
+        file.sync_all()?;
+        self.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,320 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::encryption::{EncryptionKey, Keyring};
+use crate::KvError;
+
+/// Default number of stale bytes that triggers a compaction (1 MiB).
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Default capacity of the buffered readers used for `get` (8 KiB).
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Default size below which records are not compressed (256 bytes).
+const DEFAULT_COMPRESSION_MIN_SIZE: usize = 256;
+
+/// Environment variable holding the encryption keys if no key file is given.
+const ENCRYPTION_KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+/// Controls when `KvStore` forces written records to stable storage.
+///
+/// Every write is flushed to the OS before it is acknowledged; the policy
+/// decides how often the log file is additionally `fsync`ed so that it
+/// survives a power failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Never sync explicitly; rely on the OS to write back the page cache.
+    #[default]
+    Never,
+    /// Sync after every write, before it is acknowledged.
+    Always,
+    /// Sync at most this many milliseconds after a write.
+    ///
+    /// A write arriving once the interval has passed since the previous sync
+    /// syncs the log itself; writes followed by a pause are synced by the
+    /// store's background thread when the interval runs out.
+    EveryNMillis(u64),
+    /// Sync after every N writes.
+    EveryNWrites(u64),
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KvError;
+
+    /// Parses `never`, `always`, `<N>ms` or `<N>writes`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            KvError::StringError(format!(
+                "Invalid sync policy: {s}. Must be 'never', 'always', '<N>ms' or '<N>writes'."
+            ))
+        };
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => {
+                if let Some(n) = s.strip_suffix("ms") {
+                    match n.parse() {
+                        Ok(0) | Err(_) => Err(invalid()),
+                        Ok(n) => Ok(SyncPolicy::EveryNMillis(n)),
+                    }
+                } else if let Some(n) = s.strip_suffix("writes") {
+                    match n.parse() {
+                        Ok(0) | Err(_) => Err(invalid()),
+                        Ok(n) => Ok(SyncPolicy::EveryNWrites(n)),
+                    }
+                } else {
+                    Err(invalid())
+                }
+            }
+        }
+    }
+}
+
+impl SyncPolicy {
+    /// Returns how long a write may stay unsynced under an interval-based
+    /// policy.
+    pub(super) fn interval(&self) -> Option<Duration> {
+        match *self {
+            SyncPolicy::EveryNMillis(ms) => Some(Duration::from_millis(ms)),
+            _ => None,
+        }
+    }
+}
+
+/// Codec `KvStore` compresses the records it writes with.
+///
+/// Each record names the codec it was written with, so a store can be
+/// reopened with a different one and still read its existing records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Write records uncompressed.
+    #[default]
+    None,
+    /// LZ4, which is fast with a moderate ratio.
+    Lz4,
+    /// Zstandard, which is slower with a better ratio.
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = KvError;
+
+    /// Parses `none`, `lz4` or `zstd`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(KvError::StringError(format!(
+                "Invalid compression: {s}. Must be 'none', 'lz4' or 'zstd'."
+            ))),
+        }
+    }
+}
+
+/// Options used to open a `KvStore` with [`KvStore::open_with`].
+///
+/// [`KvStore::open_with`]: super::KvStore::open_with
+///
+/// ```no_run
+/// use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+///
+/// let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+/// let store = KvStore::open_with("data", options)?;
+/// # Ok::<(), kvs::KvError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) group_commit: bool,
+    pub(super) compaction_threshold: u64,
+    pub(super) compaction_ratio: Option<f64>,
+    pub(super) max_file_size: Option<u64>,
+    pub(super) read_buffer_size: usize,
+    pub(super) compression: Compression,
+    pub(super) compression_min_size: usize,
+    pub(super) cache_size: usize,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    pub(super) previous_encryption_keys: Vec<EncryptionKey>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            sync_policy: SyncPolicy::default(),
+            group_commit: true,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: None,
+            max_file_size: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            compression: Compression::default(),
+            compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            cache_size: 0,
+            encryption_key: None,
+            previous_encryption_keys: Vec::new(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates options with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the durability policy for writes. Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Sets whether concurrent writes share a single flush and sync.
+    /// Defaults to `true`.
+    ///
+    /// With group commit disabled every write is flushed (and synced, if
+    /// the policy asks for it) on its own.
+    pub fn group_commit(mut self, enabled: bool) -> Self {
+        self.group_commit = enabled;
+        self
+    }
+
+    /// Sets how many bytes of stale records trigger a compaction.
+    /// Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Also compacts once stale records make up at least `ratio` (greater
+    /// than 0 and at most 1) of the total log size, even below the byte
+    /// threshold. Disabled by default.
+    ///
+    /// `KvStore::open_with` rejects a ratio outside that range.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = Some(ratio);
+        self
+    }
+
+    /// Sets the size at which the active log is sealed and writes move on
+    /// to a new generation. Unlimited by default.
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Sets the buffer capacity of the readers used to serve `get`.
+    /// Defaults to 8 KiB.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Sets the byte budget of an in-memory cache of recently read values.
+    /// Defaults to 0, which disables the cache.
+    ///
+    /// See [`KvStore::cache_stats`](crate::KvStore::cache_stats) for its
+    /// hit rate.
+    pub fn cache_size(mut self, bytes: usize) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// Sets the codec new records are compressed with. Defaults to
+    /// `Compression::None`.
+    ///
+    /// Compaction rewrites the records it copies with this codec.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the encoded size below which records are written uncompressed.
+    /// Defaults to 256 bytes.
+    pub fn compression_min_size(mut self, bytes: usize) -> Self {
+        self.compression_min_size = bytes;
+        self
+    }
+
+    /// Encrypts new records with `key`. Records are written in plaintext
+    /// by default.
+    ///
+    /// Compaction re-encrypts the records it copies with this key, so to
+    /// rotate keys, pass the old one to `previous_encryption_key` until a
+    /// compaction has run.
+    ///
+    /// Records are protected against being read or altered, but not
+    /// against being rolled back: a sealed record is not tied to where it
+    /// lies, so an older copy of a log, or an old record moved to the end
+    /// of one, still reads as valid.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Adds a key that only decrypts records written before the current
+    /// key was set. May be called more than once.
+    pub fn previous_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.previous_encryption_keys.push(key);
+        self
+    }
+
+    /// Reads the encryption keys from `file`, or from the environment
+    /// variable `KVS_ENCRYPTION_KEY` if no file is given. Leaves the options
+    /// unchanged if neither is set.
+    ///
+    /// Keys are base64 and separated by whitespace. The first one encrypts
+    /// new records; the others only decrypt records written before a key
+    /// change.
+    pub fn with_encryption_keys_from(mut self, file: Option<&Path>) -> crate::Result<Self> {
+        let text = match file {
+            Some(path) => fs::read_to_string(path)?,
+            None => match env::var(ENCRYPTION_KEY_ENV) {
+                Ok(text) => text,
+                Err(env::VarError::NotPresent) => return Ok(self),
+                Err(e) => {
+                    return Err(KvError::StringError(format!(
+                        "{}: {}",
+                        ENCRYPTION_KEY_ENV, e
+                    )))
+                }
+            },
+        };
+        let mut keys = text.split_whitespace().map(str::parse::<EncryptionKey>);
+        match keys.next() {
+            Some(key) => self = self.encryption_key(key?),
+            None => return Err(KvError::StringError("No encryption key given".to_owned())),
+        }
+        for key in keys {
+            self = self.previous_encryption_key(key?);
+        }
+        Ok(self)
+    }
+
+    /// Fails with `KvError::StringError` if a setting is out of range.
+    pub(super) fn validate(&self) -> crate::Result<()> {
+        if let Some(ratio) = self.compaction_ratio {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                return Err(KvError::StringError(format!(
+                    "Invalid compaction ratio: {ratio}. Must be greater than 0 and at most 1."
+                )));
+            }
+        }
+        match self.sync_policy {
+            SyncPolicy::EveryNMillis(0) | SyncPolicy::EveryNWrites(0) => Err(
+                KvError::StringError("Invalid sync policy: interval must not be 0.".to_owned()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the keys records are encrypted and decrypted with.
+    pub(super) fn keyring(&self) -> Keyring {
+        Keyring::new(
+            self.encryption_key.clone(),
+            self.previous_encryption_keys.clone(),
+        )
+    }
+}
@@ -0,0 +1,80 @@
+//! On-disk framing for log records.
+//!
+//! Every command in a `<gen>.log` file is stored as a fixed-size header
+//! followed by the serialized payload:
+//!
+//! ```text
+//! +----------------+----------------+-------------------+
+//! | len: u32 (LE)  | crc32: u32 (LE)| payload (len B)   |
+//! +----------------+----------------+-------------------+
+//! ```
+//!
+//! The CRC covers the payload only. A record whose checksum does not match
+//! is reported as corrupt instead of being handed to the deserializer.
+
+use std::io::{self, Read, Write};
+
+/// Size of the record header in bytes.
+pub(super) const HEADER_LEN: u64 = 8;
+
+/// Result of reading one frame from a log.
+pub(super) enum Frame {
+    /// A complete record whose checksum matched.
+    Record(Vec<u8>),
+    /// The reader was already at the end of the log.
+    Eof,
+    /// The log ended in the middle of a record.
+    Truncated,
+    /// The record was complete but its checksum did not match.
+    Corrupt,
+}
+
+/// Writes `payload` as a framed record, returning the total bytes written.
+pub(super) fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<u64> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[..4].copy_from_slice(&len.to_le_bytes());
+    header[4..].copy_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    Ok(HEADER_LEN + payload.len() as u64)
+}
+
+/// Reads the next framed record from `reader`.
+pub(super) fn read_frame<R: Read>(reader: &mut R) -> io::Result<Frame> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(Frame::Eof),
+        n if n < header.len() => return Ok(Frame::Truncated),
+        _ => {}
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Ok(Frame::Truncated);
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Ok(Frame::Corrupt);
+    }
+    Ok(Frame::Record(payload))
+}
+
+/// Fills `buf` as far as possible, returning the number of bytes read.
+///
+/// Unlike `read_exact`, hitting EOF early is not an error.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
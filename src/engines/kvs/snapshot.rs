@@ -0,0 +1,253 @@
+//! Point-in-time snapshots.
+//!
+//! A snapshot owns a copy of the index and reads values from the
+//! generations that copy points into. Those generations are pinned for as
+//! long as the snapshot lives: when compaction retires a pinned
+//! generation, it renames `<gen>.log` to `<gen>.retired` instead of
+//! deleting it, so that it no longer takes part in recovery but stays
+//! readable. The retired file is deleted once its last snapshot is dropped,
+//! or on the next open if the process dies first.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+use super::lock::DirLock;
+use super::{log_path, CommandPos, Index, KvStoreReader};
+use crate::engines::{BytesScanIter, KvsSnapshot};
+use crate::Result;
+
+/// Generations referenced by live snapshots, or being read for the
+/// history of a new subscription.
+pub(super) struct Pins {
+    dir: Arc<PathBuf>,
+    state: Mutex<PinState>,
+    /// Lock on `dir`, held until the store and its last snapshot are gone
+    /// so that no other open removes the retired generations they read.
+    _lock: DirLock,
+}
+
+#[derive(Default)]
+struct PinState {
+    /// Number of snapshots referencing each pinned generation.
+    counts: HashMap<u64, usize>,
+    /// Pinned generations that compaction has already retired.
+    retired: HashSet<u64>,
+}
+
+impl Pins {
+    pub(super) fn new(dir: Arc<PathBuf>, lock: DirLock) -> Self {
+        Self {
+            dir,
+            state: Mutex::new(PinState::default()),
+            _lock: lock,
+        }
+    }
+
+    /// Pins `gens` until the returned guard is dropped.
+    ///
+    /// The caller must hold a lock that keeps compaction from removing any
+    /// of them in the meantime.
+    pub(super) fn hold(self: &Arc<Self>, gens: BTreeSet<u64>) -> PinGuard {
+        let mut state = self.state.lock().unwrap();
+        for &gen in &gens {
+            *state.counts.entry(gen).or_default() += 1;
+        }
+        PinGuard {
+            pins: self.clone(),
+            gens,
+        }
+    }
+
+    /// Releases one holder's pins, deleting retired generations that no
+    /// snapshot references any more.
+    fn unpin(&self, gens: &BTreeSet<u64>) {
+        let mut state = self.state.lock().unwrap();
+        for gen in gens {
+            let Some(count) = state.counts.get_mut(gen) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                state.counts.remove(gen);
+                if state.retired.remove(gen) {
+                    if let Err(e) = fs::remove_file(retired_path(&self.dir, *gen)) {
+                        warn!("Failed to remove retired generation {}: {}", gen, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deletes the log of generation `gen`, or retires it if a snapshot
+    /// still references it.
+    pub(super) fn remove_generation(&self, gen: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.counts.contains_key(&gen) {
+            fs::rename(log_path(&self.dir, gen), retired_path(&self.dir, gen))?;
+            state.retired.insert(gen);
+        } else {
+            fs::remove_file(log_path(&self.dir, gen))?;
+        }
+        Ok(())
+    }
+}
+
+/// Releases a set of pins when dropped.
+pub(super) struct PinGuard {
+    pins: Arc<Pins>,
+    gens: BTreeSet<u64>,
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        self.pins.unpin(&self.gens);
+    }
+}
+
+/// A read-only view of a `KvStore` as of the moment it was taken.
+///
+/// Created by [`KvsEngine::snapshot`]. Keys that had expired at that moment
+/// are absent from the snapshot, and keys that expire later remain
+/// visible in it.
+///
+/// [`KvsEngine::snapshot`]: crate::KvsEngine::snapshot
+pub struct KvStoreSnapshot {
+    index: Arc<Index>,
+    /// Time the snapshot was taken, used to evaluate expiry.
+    now: u64,
+    reader: KvStoreReader,
+    pin: Arc<PinGuard>,
+}
+
+impl KvStoreSnapshot {
+    /// Takes a snapshot of `index`, which the caller holds locked so that
+    /// compaction cannot retire the generations it refers to concurrently.
+    pub(super) fn new(
+        index: &Index,
+        now: u64,
+        pins: Arc<Pins>,
+        reader: KvStoreReader,
+    ) -> KvStoreSnapshot {
+        let gens: BTreeSet<u64> = index.values().map(|cmd_pos| cmd_pos.gen).collect();
+        KvStoreSnapshot {
+            index: Arc::new(index.clone()),
+            now,
+            reader,
+            pin: Arc::new(pins.hold(gens)),
+        }
+    }
+
+    /// Starts a scan over the snapshot entries selected by `select`.
+    fn scan_index<F>(&self, select: F) -> BytesScanIter
+    where
+        F: FnOnce(&Index) -> Vec<(Vec<u8>, CommandPos)>,
+    {
+        let mut entries = select(&self.index);
+        entries.retain(|(_, cmd_pos)| !cmd_pos.is_expired(self.now));
+        Box::new(SnapshotScan {
+            snapshot: self.clone(),
+            entries: entries.into_iter(),
+        })
+    }
+
+    /// Reads the value of the record at `cmd_pos`.
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<Option<Vec<u8>>> {
+        self.reader.read_command(cmd_pos)
+    }
+}
+
+impl Clone for KvStoreSnapshot {
+    fn clone(&self) -> Self {
+        KvStoreSnapshot {
+            index: self.index.clone(),
+            now: self.now,
+            // Like `KvStore`, each clone reads through its own handles.
+            reader: self.reader.fresh(),
+            pin: self.pin.clone(),
+        }
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(self.now) => self.read_value(*cmd_pos),
+            _ => Ok(None),
+        }
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesScanIter> {
+        Ok(self.scan_index(|index| {
+            index
+                .range(range)
+                .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+                .collect()
+        }))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesScanIter> {
+        Ok(self.scan_index(|index| {
+            index
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+                .collect()
+        }))
+    }
+}
+
+/// Iterator returned by `KvStoreSnapshot::scan_bytes` and
+/// `KvStoreSnapshot::scan_prefix_bytes`.
+struct SnapshotScan {
+    snapshot: KvStoreSnapshot,
+    entries: std::vec::IntoIter<(Vec<u8>, CommandPos)>,
+}
+
+impl Iterator for SnapshotScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (key, cmd_pos) in self.entries.by_ref() {
+            match self.snapshot.read_value(cmd_pos) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+/// Deletes the generations left retired by a previous run, whose
+/// snapshots are necessarily gone.
+pub(super) fn remove_retired(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("retired".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path a retired generation is kept at.
+pub(super) fn retired_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{gen}.retired"))
+}
+
+/// Opens the log of generation `gen` for reading, falling back to the
+/// retired copy kept for snapshots.
+pub(super) fn open_log(dir: &Path, gen: u64) -> io::Result<fs::File> {
+    match fs::File::open(log_path(dir, gen)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::File::open(retired_path(dir, gen)).map_err(|_| e)
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,189 @@
+//! Change data capture.
+//!
+//! Subscribers register a channel with the writer. After each group is
+//! committed and applied to the index, the leader sends an event for every
+//! `Set` and `Remove` it appended, still under the writer `Mutex`, so
+//! events arrive in sequence order. Events wait in the channel until the
+//! subscriber reads them. The channel is bounded so that a subscriber that
+//! stops reading cannot hold on to unbounded memory: once it is full, the
+//! subscriber is dropped and marked as lagged instead of stalling writers.
+//!
+//! History comes from the log itself. At subscription time the writer
+//! records the next sequence number and the generations on disk, which are
+//! pinned like a snapshot's while they are scanned for older records.
+//! Compaction keeps only the latest record of each live key, so history
+//! that predates the last compaction is incomplete.
+
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, MutexGuard};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+
+use super::encryption::Keyring;
+use super::format;
+use super::record::{self, Frame};
+use super::snapshot::{self, Pins};
+use super::{Command, KvStoreWriter};
+use crate::engines::events::EventSource;
+use crate::engines::{Event, Subscription};
+use crate::{KvError, Result};
+
+/// Number of unread events after which a subscriber is dropped.
+const SUBSCRIBER_CAPACITY: usize = 4096;
+
+/// The writer's end of a subscription.
+pub(super) struct Subscriber {
+    tx: Sender<Event>,
+    /// Set when the subscriber is dropped for falling behind.
+    lagged: Arc<AtomicBool>,
+}
+
+/// The subscription's end of the channel.
+struct LiveEvents {
+    rx: Receiver<Event>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl EventSource for LiveEvents {
+    fn next_event(&mut self, timeout: Option<Duration>) -> Option<Event> {
+        match timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).ok(),
+            None => self.rx.recv().ok(),
+        }
+    }
+
+    fn lagged(&self) -> bool {
+        self.lagged.load(Ordering::Acquire)
+    }
+}
+
+impl Command {
+    /// Returns the event describing this command, if it is a write.
+    fn to_event(&self) -> Option<Event> {
+        match self {
+            Command::Set {
+                key, value, seq, ..
+            } => Some(Event::Set {
+                key: key.clone(),
+                value: value.clone(),
+                seq: *seq,
+            }),
+            Command::Remove { key, seq } => Some(Event::Remove {
+                key: key.clone(),
+                seq: *seq,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Sends an event for each of `commands` to the subscribers, dropping
+/// those whose subscription is gone or whose channel is full.
+pub(super) fn publish<'a>(
+    subscribers: &mut Vec<Subscriber>,
+    commands: impl Iterator<Item = &'a Command>,
+) {
+    if subscribers.is_empty() {
+        return;
+    }
+    for event in commands.filter_map(Command::to_event) {
+        subscribers.retain(|subscriber| match subscriber.tx.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                subscriber.lagged.store(true, Ordering::Release);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+/// Registers a subscriber with `writer` and collects its history.
+///
+/// The caller holds the writer `Mutex`; it is released by the time the log
+/// is scanned.
+pub(super) fn subscribe(
+    dir: &Path,
+    pins: &Arc<Pins>,
+    mut writer: MutexGuard<KvStoreWriter>,
+    from_seq: u64,
+) -> Result<Subscription> {
+    let (tx, rx) = channel::bounded(SUBSCRIBER_CAPACITY);
+    let lagged = Arc::new(AtomicBool::new(false));
+    writer.subscribers.push(Subscriber {
+        tx,
+        lagged: lagged.clone(),
+    });
+    let live = LiveEvents { rx, lagged };
+    let boundary = writer.next_seq;
+    if from_seq >= boundary {
+        return Ok(Subscription::new(Vec::new(), live));
+    }
+    let gens = writer.gens.clone();
+    let active = (writer.current_gen, writer.writer.pos);
+    let keys = writer.encoding.keys.clone();
+    let _pin = pins.hold(gens.clone());
+    drop(writer);
+
+    let mut history = Vec::new();
+    for gen in gens {
+        let file = snapshot::open_log(dir, gen)?;
+        // Records past the end of the active log at subscription time are
+        // delivered live.
+        let len = if gen == active.0 { active.1 } else { u64::MAX };
+        read_history(
+            gen,
+            BufReader::new(file.take(len)),
+            &keys,
+            from_seq,
+            boundary,
+            &mut history,
+        )?;
+    }
+    history.sort_by_key(Event::seq);
+    Ok(Subscription::new(history, live))
+}
+
+/// Appends to `history` the committed writes of generation `gen` whose
+/// sequence number lies in `from_seq..boundary`.
+fn read_history<R: Read>(
+    gen: u64,
+    mut reader: R,
+    keys: &Keyring,
+    from_seq: u64,
+    boundary: u64,
+    history: &mut Vec<Event>,
+) -> Result<()> {
+    let mut pos = 0u64;
+    // Writes of a batch whose commit marker has not been read yet.
+    let mut batch: Option<Vec<Event>> = None;
+    loop {
+        let payload = match record::read_frame(&mut reader)? {
+            Frame::Record(payload) => payload,
+            // A torn tail holds nothing that was acknowledged.
+            Frame::Eof | Frame::Truncated => return Ok(()),
+            Frame::Corrupt => return Err(KvError::Corruption { gen, pos }),
+        };
+        let cmd = format::decode(&payload, keys, gen, pos)?;
+        pos += record::HEADER_LEN + payload.len() as u64;
+        match cmd {
+            Command::BatchBegin { .. } => batch = Some(Vec::new()),
+            Command::BatchCommit => history.extend(batch.take().unwrap_or_default()),
+            cmd => {
+                let Some(event) = cmd.to_event() else {
+                    continue;
+                };
+                if !(from_seq..boundary).contains(&event.seq()) {
+                    continue;
+                }
+                match &mut batch {
+                    Some(events) => events.push(event),
+                    None => history.push(event),
+                }
+            }
+        }
+    }
+}
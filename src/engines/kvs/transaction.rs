@@ -0,0 +1,70 @@
+//! Optimistic transactions.
+//!
+//! A transaction remembers the sequence number of every key it reads. On
+//! commit, under the writer `Mutex`, it checks that each of those keys still
+//! holds the write it was read at and only then appends its buffered
+//! writes, framed as a batch.
+
+use std::collections::BTreeMap;
+
+use super::{Command, KvStore};
+use crate::engines::KvsTransaction;
+use crate::Result;
+
+/// An optimistic transaction on a `KvStore`.
+///
+/// Created by [`KvsEngine::begin`].
+///
+/// [`KvsEngine::begin`]: crate::KvsEngine::begin
+pub struct KvStoreTransaction {
+    store: KvStore,
+    /// Sequence number each read key had when first read, or `None` if it
+    /// was absent.
+    reads: BTreeMap<Vec<u8>, Option<u64>>,
+    /// Buffered writes; `None` removes the key.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl KvStoreTransaction {
+    pub(super) fn new(store: KvStore) -> Self {
+        Self {
+            store,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+}
+
+impl KvsTransaction for KvStoreTransaction {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let found = self.store.lookup_versioned(&key)?;
+        let seq = found.as_ref().map(|&(_, seq)| seq);
+        // Validation compares against the first read; a later read of a
+        // changed key makes the commit fail anyway.
+        self.reads.entry(key).or_insert(seq);
+        Ok(found.map(|(value, _)| value))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    fn commit(self) -> Result<()> {
+        let cmds = self
+            .writes
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Command::set(key, value, None),
+                None => Command::remove(key),
+            })
+            .collect();
+        self.store.commit_transaction(&self.reads, cmds)
+    }
+}
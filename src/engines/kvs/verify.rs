@@ -0,0 +1,317 @@
+//! Offline verification and repair of a data directory.
+//!
+//! Verification replays every log with the same parser as `KvStore::open`,
+//! but keeps going past a damaged generation so that the whole directory
+//! is covered, and never uses hint files. It only reads the directory;
+//! the store must not be open while it runs.
+
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use super::encryption::Keyring;
+use super::format::{self, Format};
+use super::lock::{self, DirLock};
+use super::{hint, load, log_gen, log_path, BufReaderWithPos, Index, KvStore, KvStoreOptions};
+use crate::engines::now_millis;
+use crate::{KvError, Result};
+
+/// Extensions of the files a store keeps next to its logs, each named
+/// after a generation.
+const COMPANION_EXTENSIONS: [&str; 5] = ["hint", "retired", "compacting", "restoring", "repairing"];
+
+/// Damage found in a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Damage {
+    /// The record at `pos` does not decode, or fails its checksum in a
+    /// log other than the newest.
+    Corrupt {
+        /// Offset of the record.
+        pos: u64,
+    },
+    /// The newest log ends in a torn write: the record at `pos` is cut
+    /// short or fails its checksum, or it starts a write batch that is
+    /// never committed. Opening the store cuts the log there.
+    Truncated {
+        /// Offset of the record or of the batch's begin marker.
+        pos: u64,
+    },
+}
+
+impl Damage {
+    /// Offset where the damage starts; everything before it is intact.
+    pub fn pos(&self) -> u64 {
+        match *self {
+            Damage::Corrupt { pos } | Damage::Truncated { pos } => pos,
+        }
+    }
+}
+
+/// Findings of [`KvStore::verify`] for one generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationReport {
+    /// Generation number.
+    pub gen: u64,
+    /// Size of the log in bytes.
+    pub bytes: u64,
+    /// Bytes of records holding the current, unexpired value of a key.
+    pub live_bytes: u64,
+    /// First damage in the log, if any. Records after it are not checked.
+    pub damage: Option<Damage>,
+}
+
+impl GenerationReport {
+    /// Bytes that compaction would reclaim, damaged ones included.
+    pub fn stale_bytes(&self) -> u64 {
+        self.bytes - self.live_bytes
+    }
+}
+
+/// Findings of [`KvStore::verify`] for a data directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Every generation, oldest first.
+    pub generations: Vec<GenerationReport>,
+    /// Generations named by more than one log file, such as `7.log` and
+    /// `007.log`. Only `<gen>.log` is ever read.
+    pub duplicate_gens: Vec<u64>,
+    /// Files left behind by an interrupted operation: hints without a
+    /// log, retired generations, and partial compaction or restore output.
+    pub orphaned_files: Vec<PathBuf>,
+    /// Files the store does not recognize and ignores, including logs
+    /// named other than `<gen>.log` with no such log beside them.
+    pub stray_files: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    /// Whether any log is damaged in a way `KvStore::open` rejects.
+    ///
+    /// An incomplete record at the end of the newest generation is not
+    /// counted, since opening the store discards it.
+    pub fn is_corrupt(&self) -> bool {
+        let newest = self.generations.last().map(|g| g.gen);
+        self.generations.iter().any(|g| match g.damage {
+            Some(Damage::Corrupt { .. }) => true,
+            Some(Damage::Truncated { .. }) => Some(g.gen) != newest,
+            None => false,
+        })
+    }
+
+    /// Total size of the logs in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.generations.iter().map(|g| g.bytes).sum()
+    }
+
+    /// Total bytes of live records.
+    pub fn live_bytes(&self) -> u64 {
+        self.generations.iter().map(|g| g.live_bytes).sum()
+    }
+}
+
+impl KvStore {
+    /// Checks every log of the store in `path` without opening it.
+    ///
+    /// Damage is reported in the returned [`VerifyReport`] rather than as
+    /// an error; only failures to read the directory are errors.
+    pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport> {
+        Self::verify_with(path, &KvStoreOptions::default())
+    }
+
+    /// Checks every log of the store in `path` without opening it, reading
+    /// encrypted records with the keys in `options`.
+    ///
+    /// Fails if the store may hold encrypted records and `options` has no
+    /// key. A record that the keys do not decrypt is reported as corrupt.
+    pub fn verify_with(path: impl AsRef<Path>, options: &KvStoreOptions) -> Result<VerifyReport> {
+        let path = path.as_ref();
+        let keys = options.keyring();
+        if let Some(format) = Format::read(path)? {
+            format::check_keys(format, &keys)?;
+        }
+        let mut report = VerifyReport::default();
+        let mut logs = BTreeSet::new();
+        let mut misnamed = Vec::new();
+        let mut companions = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let file = entry?.path();
+            if let Some(gen) = log_gen(&file) {
+                logs.insert(gen);
+            } else if let Some(gen) = misnamed_log_gen(&file) {
+                misnamed.push((gen, file));
+            } else if let Some(gen) = companion_gen(&file) {
+                companions.push((gen, file));
+            } else if file != format::marker_path(path)
+                && file != lock::lock_path(path)
+                && file.file_name() != Some("engine".as_ref())
+            {
+                report.stray_files.push(file);
+            }
+        }
+        for (gen, file) in companions {
+            let is_hint = file.extension() == Some("hint".as_ref());
+            if !(is_hint && logs.contains(&gen)) {
+                report.orphaned_files.push(file);
+            }
+        }
+        for (gen, file) in misnamed {
+            if logs.contains(&gen) {
+                report.duplicate_gens.push(gen);
+            } else {
+                report.stray_files.push(file);
+            }
+        }
+        report.orphaned_files.sort();
+        report.stray_files.sort();
+        report.duplicate_gens.sort_unstable();
+        report.duplicate_gens.dedup();
+
+        let mut index = Index::new();
+        let newest = logs.last().copied();
+        for &gen in &logs {
+            let mut reader = BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
+            let bytes = reader.seek(SeekFrom::End(0))?;
+            let is_newest = Some(gen) == newest;
+            let damage = match load(gen, &mut reader, &mut index, &keys, is_newest) {
+                Ok(loaded) => loaded.torn_tail.map(|pos| Damage::Truncated { pos }),
+                Err(KvError::Corruption { pos, .. }) => Some(Damage::Corrupt { pos }),
+                Err(e) => return Err(e),
+            };
+            report.generations.push(GenerationReport {
+                gen,
+                bytes,
+                live_bytes: 0,
+                damage,
+            });
+        }
+        let now = now_millis();
+        for cmd_pos in index.values().filter(|cmd_pos| !cmd_pos.is_expired(now)) {
+            if let Ok(i) = report
+                .generations
+                .binary_search_by_key(&cmd_pos.gen, |g| g.gen)
+            {
+                report.generations[i].live_bytes += cmd_pos.len;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Verifies the store in `path` and cuts every damaged log back to the
+    /// records before the damage, returning the report from before the
+    /// repair.
+    ///
+    /// Records after the damage are lost. In the newest generation these
+    /// are only the last writes; in an older one, keys they wrote fall back
+    /// to the values of still older generations even though newer writes
+    /// were acknowledged after them. Such generations are only cut if
+    /// `force` is set; otherwise the repair fails without changing
+    /// anything. A write batch the cut would split is dropped as a whole.
+    ///
+    /// Each log is cut by writing its intact records to a new file that
+    /// replaces it, so that a backup sharing the old file through a hard
+    /// link keeps its copy. Fails if the store is open.
+    pub fn repair(path: impl AsRef<Path>, force: bool) -> Result<VerifyReport> {
+        Self::repair_with(path, &KvStoreOptions::default(), force)
+    }
+
+    /// Verifies and repairs the store in `path` like [`KvStore::repair`],
+    /// reading encrypted records with the keys in `options`.
+    ///
+    /// Records that the keys do not decrypt count as damage, so repairing
+    /// with the wrong key discards them.
+    pub fn repair_with(
+        path: impl AsRef<Path>,
+        options: &KvStoreOptions,
+        force: bool,
+    ) -> Result<VerifyReport> {
+        let path = path.as_ref();
+        let _lock = DirLock::acquire(path)?;
+        let keys = options.keyring();
+        let report = Self::verify_with(path, options)?;
+        let newest = report.generations.last().map(|g| g.gen);
+        let sealed: Vec<u64> = report
+            .generations
+            .iter()
+            .filter(|g| g.damage.is_some() && Some(g.gen) != newest)
+            .map(|g| g.gen)
+            .collect();
+        if !sealed.is_empty() && !force {
+            return Err(KvError::StringError(format!(
+                "Damaged generations {:?} are not the newest and cutting them reverts \
+                 keys to older values; repair with force to cut them anyway",
+                sealed
+            )));
+        }
+        for generation in &report.generations {
+            let Some(damage) = generation.damage else {
+                continue;
+            };
+            let gen = generation.gen;
+            let len = cut_log(path, gen, damage.pos(), &keys)?;
+            hint::remove_hint(path, gen)?;
+            warn!(
+                "Truncated {} to {} bytes, discarding {}",
+                log_path(path, gen).display(),
+                len,
+                generation.bytes - len
+            );
+        }
+        Ok(report)
+    }
+}
+
+/// Replaces log `gen` with a copy of its first `len` bytes, less a write
+/// batch the cut would split, and returns the length of the copy.
+fn cut_log(path: &Path, gen: u64, len: u64, keys: &Keyring) -> Result<u64> {
+    let log = log_path(path, gen);
+    let repairing = repairing_path(path, gen);
+    let mut copy = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&repairing)?;
+    io::copy(&mut File::open(&log)?.take(len), &mut copy)?;
+    let mut len = len;
+    // Cutting inside a write batch leaves it without its commit marker,
+    // which a second pass removes.
+    loop {
+        let mut reader = BufReaderWithPos::new(copy.try_clone()?)?;
+        match load(gen, &mut reader, &mut Index::new(), keys, true)?.torn_tail {
+            Some(pos) => {
+                copy.set_len(pos)?;
+                len = pos;
+            }
+            None => break,
+        }
+    }
+    copy.sync_all()?;
+    fs::rename(&repairing, &log)?;
+    File::open(path)?.sync_all()?;
+    Ok(len)
+}
+
+/// Returns the temporary path a log is repaired to.
+fn repairing_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{gen}.repairing"))
+}
+
+/// Returns the generation a log file named other than `<gen>.log`, such
+/// as `007.log`, would belong to.
+fn misnamed_log_gen(path: &Path) -> Option<u64> {
+    if !path.is_file() || path.extension() != Some("log".as_ref()) {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Returns the generation a companion file of a log belongs to.
+fn companion_gen(path: &Path) -> Option<u64> {
+    let extension = path.extension()?.to_str()?;
+    if !path.is_file() || !COMPANION_EXTENSIONS.contains(&extension) {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
@@ -1,31 +1,381 @@
+use std::io::{BufRead, Write};
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use crate::Result;
 
+/// Iterator over key/value pairs in ascending key order, returned by
+/// [`KvsEngine::scan`] and [`KvsEngine::scan_prefix`].
+pub type ScanIter = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Iterator over binary key/value pairs in ascending byte order, returned
+/// by [`KvsEngine::scan_bytes`] and [`KvsEngine::scan_prefix_bytes`].
+pub type BytesScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Number of pairs `KvsEngine::import` applies per write batch.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
 /// Trait for a key-value storage engine.
 ///
 /// Implementors provide persistent key-value storage with
 /// set, get, and remove operations.
 ///
+/// Keys and values are arbitrary bytes. The `String` methods are
+/// conveniences over the `_bytes` ones; those that return values fail
+/// with `KvError::Utf8` when a stored key or value is not valid UTF-8.
+///
 /// Engines must be cloneable (cheaply, via `Arc`) and safe to
 /// send across threads, enabling concurrent access from a thread pool.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Read-only point-in-time view returned by [`KvsEngine::snapshot`].
+    type Snapshot: KvsSnapshot;
+
+    /// Optimistic transaction returned by [`KvsEngine::begin`].
+    type Transaction: KvsTransaction;
+
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// Once expired the key is treated as absent. Setting the key again
+    /// without a TTL makes it permanent.
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the key does not exist.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the value of a given key along with its version.
+    ///
+    /// The version is a sequence number taken from a counter shared by
+    /// every write to the engine, so it grows with each write of the key
+    /// and survives restarts. Values written by a version of the engine
+    /// that did not track versions have version 0.
+    fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>>;
+
+    /// Gets the time at which a given key expires.
+    ///
+    /// Returns `None` if the key does not exist or was set without a TTL.
+    /// A key that has just expired may still report its expiry time, which
+    /// is then in the past.
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<SystemTime>>;
+
+    /// Removes a given key.
+    ///
+    /// Returns an error if the key does not exist.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Returns the key/value pairs whose key lies in `range`, in key order.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesScanIter>;
+
+    /// Returns the key/value pairs whose key starts with `prefix`, in key
+    /// order.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesScanIter>;
+
+    /// Applies every write of `batch` atomically.
+    ///
+    /// After a crash either all of the batch is visible or none of it.
+    /// Removals of keys that do not exist are skipped.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Sets `key` to `new` if its current value is `expected`.
+    ///
+    /// `None` stands for an absent key, both as the expected value and as
+    /// the new one (which removes the key). Returns whether the swap was
+    /// made; the comparison and the write are atomic with respect to every
+    /// other write to the engine.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Takes a read-only snapshot of the engine.
+    ///
+    /// Reads through the snapshot see every write made before it was
+    /// taken and none made after, however long it is kept.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Starts an optimistic transaction.
+    ///
+    /// The transaction buffers its writes and applies them atomically on
+    /// [`KvsTransaction::commit`], provided no key it read was changed by
+    /// another write in the meantime.
+    fn begin(&self) -> Result<Self::Transaction>;
+
+    /// Subscribes to the writes made to the engine.
+    ///
+    /// The subscription first yields the past writes with a sequence
+    /// number of at least `from_seq` that the engine still holds, then
+    /// every write committed after this call, all in commit order. Pass
+    /// `u64::MAX` to receive new writes only. Keys that expire produce no
+    /// event.
+    ///
+    /// `KvStore` drops a subscription that leaves several thousand events
+    /// unread rather than queue events without bound; see
+    /// [`Subscription::lagged`].
+    ///
+    /// `SledKvsEngine` keeps no history and ignores `from_seq`. Its events
+    /// are ordered for each key but may interleave differently across
+    /// keys, and carry the version the key has when the event is read (0
+    /// for removals).
+    fn subscribe(&self, from_seq: u64) -> Result<Subscription>;
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a string key to a string that expires after
+    /// `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Gets the string value of a given string key along with its version.
+    ///
+    /// See [`KvsEngine::get_versioned_bytes`].
+    fn get_versioned(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.get_versioned_bytes(key.into_bytes())?
+            .map(|(value, version)| Ok((String::from_utf8(value)?, version)))
+            .transpose()
+    }
+
+    /// Gets the time at which a given string key expires.
+    ///
+    /// See [`KvsEngine::expiry_bytes`].
+    fn expiry(&self, key: String) -> Result<Option<SystemTime>> {
+        self.expiry_bytes(key.into_bytes())
+    }
 
     /// Removes a given key.
     ///
     /// Returns an error if the key does not exist.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Returns the key/value pairs whose key lies in `range`, in key order.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<ScanIter> {
+        Ok(Box::new(
+            self.scan_bytes(byte_range(range))?.map(decode_pair),
+        ))
+    }
+
+    /// Returns the key/value pairs whose key starts with `prefix`, in key
+    /// order.
+    fn scan_prefix(&self, prefix: String) -> Result<ScanIter> {
+        Ok(Box::new(
+            self.scan_prefix_bytes(prefix.into_bytes())?
+                .map(decode_pair),
+        ))
+    }
+
+    /// Sets string `key` to `new` if its current value is `expected`.
+    ///
+    /// See [`KvsEngine::compare_and_swap_bytes`].
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Returns every live pair in key order, as read by
+    /// [`KvsEngine::export`].
+    ///
+    /// By default the pairs are read from a snapshot, so the dump is
+    /// consistent even while other writes go on.
+    fn export_pairs(&self) -> Result<BytesScanIter> {
+        self.snapshot()?.scan_bytes(..)
+    }
+
+    /// Writes every live pair to `writer` in JSON Lines format, one
+    /// `{"key":..,"value":..}` object per line in key order.
+    ///
+    /// The pairs come from [`KvsEngine::export_pairs`]. Returns the number
+    /// of pairs written.
+    fn export<W: Write>(&self, writer: W) -> Result<u64> {
+        export::write_pairs(self.export_pairs()?, writer)
+    }
+
+    /// Sets every pair of a dump written by [`KvsEngine::export`].
+    ///
+    /// Existing keys not in the dump are kept. The pairs are applied in
+    /// batches, so a failure can leave part of the dump imported. Returns
+    /// the number of pairs imported.
+    fn import<R: BufRead>(&self, reader: R) -> Result<u64> {
+        let mut count = 0;
+        let mut batch = WriteBatch::new();
+        for pair in export::read_pairs(reader) {
+            let (key, value) = pair?;
+            batch.set_bytes(key, value);
+            if batch.len() == IMPORT_BATCH_SIZE {
+                count += batch.len() as u64;
+                self.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        count += batch.len() as u64;
+        if !batch.is_empty() {
+            self.write_batch(batch)?;
+        }
+        Ok(count)
+    }
+}
+
+/// A read-only view of an engine as of the moment it was taken.
+///
+/// Created by [`KvsEngine::snapshot`]. The `String` methods are
+/// conveniences over the `_bytes` ones, as on `KvsEngine`.
+pub trait KvsSnapshot: Clone + Send + 'static {
+    /// Gets the value a key had when the snapshot was taken.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the key/value pairs whose key lies in `range`, in key order.
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesScanIter>;
+
+    /// Returns the key/value pairs whose key starts with `prefix`, in key
+    /// order.
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesScanIter>;
+
+    /// Gets the string value a string key had when the snapshot was taken.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Returns the key/value pairs whose key lies in `range`, in key order.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<ScanIter> {
+        Ok(Box::new(
+            self.scan_bytes(byte_range(range))?.map(decode_pair),
+        ))
+    }
+
+    /// Returns the key/value pairs whose key starts with `prefix`, in key
+    /// order.
+    fn scan_prefix(&self, prefix: String) -> Result<ScanIter> {
+        Ok(Box::new(
+            self.scan_prefix_bytes(prefix.into_bytes())?
+                .map(decode_pair),
+        ))
+    }
+}
+
+/// A read-then-write transaction created by [`KvsEngine::begin`].
+///
+/// Reads go to the engine, except for keys the transaction has written,
+/// which read back the buffered value. Writes are buffered until
+/// `commit`, and dropping the transaction without committing discards
+/// them. As in a `WriteBatch`, removing a key that does not exist is not
+/// an error.
+pub trait KvsTransaction: Send + 'static {
+    /// Gets the value of a key and records the read for validation.
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Buffers a write of `value` to `key`.
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>);
+
+    /// Buffers a removal of `key`.
+    fn remove_bytes(&mut self, key: Vec<u8>);
+
+    /// Applies the buffered writes atomically.
+    ///
+    /// Fails with `KvError::Conflict`, writing nothing, if a key read by
+    /// the transaction has changed since it was read.
+    fn commit(self) -> Result<()>;
+
+    /// Gets the string value of a string key and records the read.
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Buffers a write of string `value` to string `key`.
+    fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Buffers a removal of string `key`.
+    fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes())
+    }
+}
+
+/// Converts a range of string keys into the equivalent range of bytes.
+fn byte_range<R: RangeBounds<String>>(range: R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (
+        range.start_bound().map(|key| key.clone().into_bytes()),
+        range.end_bound().map(|key| key.clone().into_bytes()),
+    )
+}
+
+/// Returns the current time in milliseconds since the Unix epoch, the unit
+/// in which key expiry times are stored.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Returns the expiry time of a key written now with the given TTL.
+fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Converts an expiry time into milliseconds since the Unix epoch.
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Converts an expiry time stored in milliseconds since the Unix epoch.
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Converts a binary key/value pair into strings.
+fn decode_pair(pair: Result<(Vec<u8>, Vec<u8>)>) -> Result<(String, String)> {
+    let (key, value) = pair?;
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
 }
 
+mod batch;
+mod events;
+pub(crate) mod export;
 mod kvs;
 mod sled_engine;
 
-pub use self::kvs::KvStore;
-pub use self::sled_engine::SledKvsEngine;
+pub use self::batch::WriteBatch;
+pub use self::events::{Event, Subscription};
+pub use self::kvs::{
+    CacheStats, Compression, Damage, EncryptionKey, GenerationReport, KvStore, KvStoreOptions,
+    KvStoreSnapshot, KvStoreTransaction, RecoveryReport, SyncPolicy, VerifyReport,
+};
+pub use self::sled_engine::{SledKvsEngine, SledSnapshot, SledTransaction};
@@ -1,44 +1,443 @@
-use sled::Db;
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
-use super::KvsEngine;
+use sled::transaction::{
+    abort, ConflictableTransactionResult, TransactionError, TransactionalTree,
+};
+use sled::{Db, IVec, Subscriber, Transactional, Tree};
+
+use super::batch::BatchOp;
+use super::events::EventSource;
+use super::{
+    expiry_after, from_millis, now_millis, BytesScanIter, Event, KvsEngine, KvsSnapshot,
+    KvsTransaction, Subscription, WriteBatch,
+};
 use crate::{KvError, Result};
 
+/// Name of the tree mapping keys with a TTL to their expiry time.
+const TTL_TREE: &str = "__kvs_ttl";
+
+/// Name of the tree mapping keys to the version of their value.
+const VERSION_TREE: &str = "__kvs_version";
+
 /// A key-value store backed by the `sled` embedded database.
 ///
 /// `sled::Db` is internally `Arc`-based, so cloning is cheap
 /// and thread-safe by design.
+///
+/// Expiry times and versions are kept in separate trees and updated in
+/// the same transaction as the values they apply to. Versions come from
+/// `Db::generate_id`, which sled keeps increasing across restarts.
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    /// Held shared by writes and exclusively while a snapshot copies the
+    /// database, so that the copy is consistent.
+    snapshot_lock: Arc<RwLock<()>>,
 }
 
 impl SledKvsEngine {
     /// Creates a new `SledKvsEngine` from an already-opened sled `Db`.
     pub fn new(db: Db) -> Self {
-        Self { db }
+        Self {
+            db,
+            snapshot_lock: Arc::new(RwLock::new(())),
+        }
+    }
+
+    /// Returns the tree of expiry times.
+    fn ttl_tree(&self) -> Result<Tree> {
+        Ok(self.db.open_tree(TTL_TREE)?)
+    }
+
+    /// Runs `f` as one transaction over the data, TTL and version trees.
+    fn transaction<A>(
+        &self,
+        f: impl Fn(&Trees) -> ConflictableTransactionResult<A, KvError>,
+    ) -> Result<A> {
+        let ttl = self.ttl_tree()?;
+        let versions = self.db.open_tree(VERSION_TREE)?;
+        let _write = self.snapshot_lock.read().unwrap();
+        (&*self.db, &ttl, &versions)
+            .transaction(|(data, ttl, versions)| {
+                f(&Trees {
+                    data,
+                    ttl,
+                    versions,
+                })
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })
+    }
+
+    /// Writes `value` to `key` with the given expiry time, or none.
+    fn put(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        self.transaction(|trees| trees.write(key, Some(value), expires_at))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Drops the pairs of `iter` whose key has expired.
+    fn live_pairs(&self, iter: sled::Iter) -> Result<BytesScanIter> {
+        let ttl = self.ttl_tree()?;
+        let now = now_millis();
+        Ok(Box::new(iter.map(to_pair).filter_map(move |pair| {
+            let expired = match &pair {
+                Ok((key, _)) => ttl.get(key).map(|t| is_expired(t.as_ref(), now)),
+                Err(_) => Ok(false),
+            };
+            match expired {
+                Ok(true) => None,
+                Ok(false) => Some(pair),
+                Err(e) => Some(Err(e.into())),
+            }
+        })))
+    }
+}
+
+/// The trees of a `SledKvsEngine` inside one transaction.
+struct Trees<'a> {
+    data: &'a TransactionalTree,
+    ttl: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
+}
+
+impl Trees<'_> {
+    /// Returns the value of `key` unless it is absent or expired at `now`.
+    fn live(&self, key: &[u8], now: u64) -> ConflictableTransactionResult<Option<IVec>, KvError> {
+        match self.data.get(key)? {
+            Some(_) if is_expired(self.ttl.get(key)?.as_ref(), now) => Ok(None),
+            value => Ok(value),
+        }
+    }
+
+    /// Returns the version of the value of `key`, or `None` if it is
+    /// absent or expired at `now`. Values written before versions were
+    /// tracked have version 0.
+    fn version(&self, key: &[u8], now: u64) -> ConflictableTransactionResult<Option<u64>, KvError> {
+        if self.live(key, now)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.versions.get(key)?.as_ref().map_or(0, decode_version),
+        ))
+    }
+
+    /// Writes `value` to `key` with the given expiry time and a new
+    /// version, or removes `key` if `value` is `None`.
+    fn write(
+        &self,
+        key: &[u8],
+        value: Option<&[u8]>,
+        expires_at: Option<u64>,
+    ) -> ConflictableTransactionResult<(), KvError> {
+        match value {
+            Some(value) => {
+                self.data.insert(key, value)?;
+                let version = self.data.generate_id()?;
+                self.versions.insert(key, &version.to_be_bytes())?;
+            }
+            None => {
+                self.data.remove(key)?;
+                self.versions.remove(key)?;
+            }
+        }
+        match expires_at {
+            Some(expires_at) => self.ttl.insert(key, &expires_at.to_be_bytes())?,
+            None => self.ttl.remove(key)?,
+        };
+        Ok(())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_bytes(), value.as_bytes())?;
+    type Snapshot = SledSnapshot;
+    type Transaction = SledTransaction;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put(&key, &value, None)
+    }
+
+    fn set_with_ttl_bytes(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.put(&key, &value, Some(expiry_after(ttl)))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let Some(value) = self.db.get(&key)? else {
+            return Ok(None);
+        };
+        if is_expired(self.ttl_tree()?.get(&key)?.as_ref(), now_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(value.to_vec()))
+    }
+
+    fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, u64)>> {
+        let now = now_millis();
+        self.transaction(|trees| {
+            let Some(value) = trees.live(&key, now)? else {
+                return Ok(None);
+            };
+            let version = trees.version(&key, now)?.unwrap_or_default();
+            Ok(Some((value.to_vec(), version)))
+        })
+    }
+
+    fn expiry_bytes(&self, key: Vec<u8>) -> Result<Option<SystemTime>> {
+        if !self.db.contains_key(&key)? {
+            return Ok(None);
+        }
+        Ok(self
+            .ttl_tree()?
+            .get(&key)?
+            .and_then(|bytes| <[u8; 8]>::try_from(bytes.as_ref()).ok())
+            .map(|bytes| from_millis(u64::from_be_bytes(bytes))))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let now = now_millis();
+        let existed = self.transaction(|trees| {
+            let existed = trees.live(&key, now)?.is_some();
+            trees.write(&key, None, None)?;
+            Ok(existed)
+        })?;
+        if !existed {
+            return Err(KvError::KeyNotFound);
+        }
         self.db.flush()?;
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .db
-            .get(key.as_bytes())?
-            .map(|ivec| String::from_utf8(ivec.to_vec()))
-            .transpose()?)
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesScanIter> {
+        self.live_pairs(self.db.range(range))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesScanIter> {
+        self.live_pairs(self.db.scan_prefix(prefix))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.db
-            .remove(key.as_bytes())?
-            .ok_or(KvError::KeyNotFound)?;
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.transaction(|trees| {
+            for op in &batch.ops {
+                match op {
+                    BatchOp::Set {
+                        key,
+                        value,
+                        expires_at,
+                    } => trees.write(key, Some(value), *expires_at)?,
+                    BatchOp::Remove { key } => trees.write(key, None, None)?,
+                }
+            }
+            Ok(())
+        })?;
         self.db.flush()?;
         Ok(())
     }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let now = now_millis();
+        let swapped = self.transaction(|trees| {
+            if trees.live(&key, now)?.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            trees.write(&key, new.as_deref(), None)?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.db.flush()?;
+        }
+        Ok(swapped)
+    }
+
+    /// Sled has no snapshots of its own, so this copies every live pair
+    /// while writes are held off. It costs time and memory proportional
+    /// to the size of the database.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let _copying = self.snapshot_lock.write().unwrap();
+        let pairs = self
+            .live_pairs(self.db.iter())?
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        Ok(SledSnapshot {
+            pairs: Arc::new(pairs),
+        })
+    }
+
+    /// Streams the pairs from a sled iterator rather than a snapshot, so
+    /// that an export neither holds off writes nor copies the database
+    /// into memory. Each pair is read as the iterator reaches it: sled
+    /// keeps every single pair consistent, but a write made during the
+    /// export may or may not be part of it.
+    fn export_pairs(&self) -> Result<BytesScanIter> {
+        self.scan_bytes(..)
+    }
+
+    fn begin(&self) -> Result<SledTransaction> {
+        Ok(SledTransaction {
+            engine: self.clone(),
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+
+    fn subscribe(&self, _from_seq: u64) -> Result<Subscription> {
+        let events = SledEvents {
+            subscriber: self.db.watch_prefix(vec![]),
+            versions: self.db.open_tree(VERSION_TREE)?,
+        };
+        Ok(Subscription::new(Vec::new(), events))
+    }
+}
+
+/// Live events of a `SledKvsEngine`, read from a sled `Subscriber` on the
+/// data tree.
+struct SledEvents {
+    subscriber: Subscriber,
+    versions: Tree,
+}
+
+impl EventSource for SledEvents {
+    fn next_event(&mut self, timeout: Option<Duration>) -> Option<Event> {
+        let event = match timeout {
+            Some(timeout) => self.subscriber.next_timeout(timeout).ok()?,
+            None => self.subscriber.next()?,
+        };
+        Some(match event {
+            sled::Event::Insert { key, value } => {
+                // The version tree is written in the same transaction, but
+                // sled publishes each tree's changes separately.
+                let seq = self
+                    .versions
+                    .get(&key)
+                    .ok()
+                    .flatten()
+                    .as_ref()
+                    .map_or(0, decode_version);
+                Event::Set {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                    seq,
+                }
+            }
+            sled::Event::Remove { key } => Event::Remove {
+                key: key.to_vec(),
+                seq: 0,
+            },
+        })
+    }
+}
+
+/// A read-only copy of a `SledKvsEngine` as of the moment it was taken.
+///
+/// Created by [`KvsEngine::snapshot`].
+#[derive(Clone)]
+pub struct SledSnapshot {
+    pairs: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.pairs.get(&key).cloned())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesScanIter> {
+        Ok(copy_pairs(self.pairs.range(range)))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Result<BytesScanIter> {
+        Ok(copy_pairs(
+            self.pairs
+                .range(prefix.clone()..)
+                .take_while(|(key, _)| key.starts_with(&prefix)),
+        ))
+    }
+}
+
+/// An optimistic transaction on a `SledKvsEngine`.
+///
+/// Created by [`KvsEngine::begin`]. The commit validates reads against
+/// the versions of the keys in the same sled transaction that applies the
+/// writes.
+pub struct SledTransaction {
+    engine: SledKvsEngine,
+    /// Version each read key had when first read, or `None` if it was
+    /// absent.
+    reads: BTreeMap<Vec<u8>, Option<u64>>,
+    /// Buffered writes; `None` removes the key.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl KvsTransaction for SledTransaction {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let found = self.engine.get_versioned_bytes(key.clone())?;
+        let version = found.as_ref().map(|&(_, version)| version);
+        self.reads.entry(key).or_insert(version);
+        Ok(found.map(|(value, _)| value))
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    fn commit(self) -> Result<()> {
+        let now = now_millis();
+        self.engine.transaction(|trees| {
+            for (key, &read) in &self.reads {
+                if trees.version(key, now)? != read {
+                    return abort(KvError::Conflict);
+                }
+            }
+            for (key, value) in &self.writes {
+                trees.write(key, value.as_deref(), None)?;
+            }
+            Ok(())
+        })?;
+        if !self.writes.is_empty() {
+            self.engine.db.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the expiry time stored in the TTL tree, if any, is at or before
+/// `now`.
+fn is_expired(expires_at: Option<&IVec>, now: u64) -> bool {
+    expires_at
+        .and_then(|bytes| <[u8; 8]>::try_from(bytes.as_ref()).ok())
+        .is_some_and(|bytes| u64::from_be_bytes(bytes) <= now)
+}
+
+/// Decodes a version stored big-endian in the version tree, reading
+/// malformed entries as 0.
+fn decode_version(bytes: &IVec) -> u64 {
+    <[u8; 8]>::try_from(bytes.as_ref()).map_or(0, u64::from_be_bytes)
+}
+
+/// Copies the pairs borrowed from a snapshot into an owned iterator.
+fn copy_pairs<'a>(pairs: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>) -> BytesScanIter {
+    let pairs: Vec<_> = pairs
+        .map(|(key, value)| Ok((key.clone(), value.clone())))
+        .collect();
+    Box::new(pairs.into_iter())
+}
+
+/// Converts a pair yielded by a sled iterator into owned bytes.
+fn to_pair(pair: sled::Result<(IVec, IVec)>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (key, value) = pair?;
+    Ok((key.to_vec(), value.to_vec()))
 }
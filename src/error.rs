@@ -25,6 +25,20 @@ pub enum KvError {
     #[error("Log file not found for generation {0}")]
     LogFileNotFound(u64),
 
+    /// A log record failed its integrity check.
+    #[error("Corrupt record in generation {gen} at offset {pos}")]
+    Corruption {
+        /// Generation of the log file holding the record.
+        gen: u64,
+        /// Byte offset of the record within that file.
+        pos: u64,
+    },
+
+    /// A transaction read a key that another write changed before the
+    /// transaction committed.
+    #[error("Transaction conflict")]
+    Conflict,
+
     /// Sled database error.
     #[error("sled error: {0}")]
     Sled(#[from] sled::Error),
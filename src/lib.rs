@@ -8,15 +8,21 @@
 
 mod client;
 mod common;
+mod encoding;
 mod engines;
 mod error;
 mod server;
 /// Thread pool implementations for concurrent request handling.
 pub mod thread_pool;
 
-pub use client::KvsClient;
+pub use client::{KvsClient, Watch};
 pub use common::{Request, Response};
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    BytesScanIter, CacheStats, Compression, Damage, EncryptionKey, Event, GenerationReport,
+    KvStore, KvStoreOptions, KvStoreSnapshot, KvStoreTransaction, KvsEngine, KvsSnapshot,
+    KvsTransaction, RecoveryReport, ScanIter, SledKvsEngine, SledSnapshot, SledTransaction,
+    Subscription, SyncPolicy, VerifyReport, WriteBatch,
+};
 pub use error::{KvError, Result};
 pub use server::KvsServer;
 pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
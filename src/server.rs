@@ -1,14 +1,24 @@
-use std::io::BufWriter;
-use std::io::Write;
+use std::io::{self, BufWriter, Write};
 use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use log::{debug, error};
 use serde_json::Deserializer;
 
 use crate::common::{Request, Response};
-use crate::engines::KvsEngine;
+use crate::engines::{KvsEngine, KvsTransaction, Subscription};
 use crate::thread_pool::ThreadPool;
-use crate::Result;
+use crate::{KvError, Result};
+
+/// How long a watching connection waits for an event before checking
+/// whether the client is still there.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default number of watches a server streams at once.
+const DEFAULT_MAX_WATCHERS: usize = 256;
 
 /// The server of a key-value store.
 ///
@@ -17,12 +27,26 @@ use crate::Result;
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
+    watchers: Arc<Watchers>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     /// Creates a `KvsServer` with a given storage engine and thread pool.
     pub fn new(engine: E, pool: P) -> Self {
-        Self { engine, pool }
+        Self {
+            engine,
+            pool,
+            watchers: Arc::new(Watchers::new(DEFAULT_MAX_WATCHERS)),
+        }
+    }
+
+    /// Sets how many watches the server streams at once (default 256).
+    ///
+    /// Each watch takes a thread of its own; a `Watch` request beyond the
+    /// limit is answered with an error.
+    pub fn max_watchers(mut self, max: usize) -> Self {
+        self.watchers = Arc::new(Watchers::new(max));
+        self
     }
 
     /// Runs the server, listening for connections on the given address.
@@ -35,8 +59,9 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
             match stream {
                 Ok(stream) => {
                     let engine = self.engine.clone();
+                    let watchers = self.watchers.clone();
                     self.pool.spawn(move || {
-                        if let Err(e) = handle_connection(engine, stream) {
+                        if let Err(e) = handle_connection(engine, stream, &watchers) {
                             error!("Error handling connection: {}", e);
                         }
                     });
@@ -50,31 +75,51 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
 }
 
 /// Handles a single client connection.
-fn handle_connection<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+fn handle_connection<E: KvsEngine>(
+    engine: E,
+    stream: TcpStream,
+    watchers: &Arc<Watchers>,
+) -> Result<()> {
     let peer_addr = stream.peer_addr()?;
     debug!("Accepted connection from {}", peer_addr);
 
     let reader = &stream;
     let mut writer = BufWriter::new(&stream);
     let requests = Deserializer::from_reader(reader).into_iter::<Request>();
+    // Transaction opened by a `Begin` on this connection, discarded if the
+    // client disconnects before committing it.
+    let mut transaction = None;
 
     for request in requests {
         let request = request?;
         debug!("Received request from {}: {:?}", peer_addr, request);
 
-        let response = match request {
-            Request::Set { key, value } => match engine.set(key, value) {
-                Ok(()) => Response::Ok(None),
-                Err(e) => Response::Err(e.to_string()),
-            },
-            Request::Get { key } => match engine.get(key) {
-                Ok(value) => Response::Ok(value),
-                Err(e) => Response::Err(e.to_string()),
-            },
-            Request::Remove { key } => match engine.remove(key) {
-                Ok(()) => Response::Ok(None),
-                Err(e) => Response::Err(e.to_string()),
+        let response = match (&mut transaction, request) {
+            (None, Request::Watch { prefix }) => match Watchers::acquire(watchers) {
+                Some(slot) => match engine.subscribe(u64::MAX) {
+                    Ok(subscription) => {
+                        serde_json::to_writer(&mut writer, &Response::Ok(None))?;
+                        writer.flush()?;
+                        // A watch lasts as long as the client stays connected,
+                        // so it gets a thread of its own instead of a worker.
+                        let stream = stream.try_clone()?;
+                        thread::spawn(move || {
+                            let _slot = slot;
+                            if let Err(e) = stream_events(&stream, subscription, &prefix) {
+                                error!("Error streaming events to {}: {}", peer_addr, e);
+                            }
+                        });
+                        return Ok(());
+                    }
+                    Err(e) => Response::Err(e.to_string()),
+                },
+                None => Response::Err("Too many watches".to_owned()),
             },
+            (None, Request::Export) => send_export(&engine, &mut writer),
+            (Some(txn), request) if !ends_transaction(&request) => {
+                handle_in_transaction(txn, request)
+            }
+            (_, request) => handle_request(&engine, &mut transaction, request),
         };
 
         serde_json::to_writer(&mut writer, &response)?;
@@ -83,3 +128,205 @@ fn handle_connection<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
 
     Ok(())
 }
+
+/// Handles a request made outside a transaction, or one that ends it.
+fn handle_request<E: KvsEngine>(
+    engine: &E,
+    transaction: &mut Option<E::Transaction>,
+    request: Request,
+) -> Response {
+    match request {
+        Request::Set {
+            key,
+            value,
+            ttl_millis,
+        } => {
+            let result = match ttl_millis {
+                Some(ms) => engine.set_with_ttl_bytes(key, value, Duration::from_millis(ms)),
+                None => engine.set_bytes(key, value),
+            };
+            match result {
+                Ok(()) => Response::Ok(None),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::Get { key } => match engine.get_bytes(key) {
+            Ok(value) => Response::Ok(value),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::GetVersioned { key } => match engine.get_versioned_bytes(key) {
+            Ok(Some((value, version))) => Response::Versioned { value, version },
+            Ok(None) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Remove { key } => match engine.remove_bytes(key) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Cas { key, expected, new } => {
+            match engine.compare_and_swap_bytes(key, expected, new) {
+                Ok(swapped) => Response::Swapped(swapped),
+                Err(e) => Response::Err(e.to_string()),
+            }
+        }
+        Request::WriteBatch(batch) => match engine.write_batch(batch) {
+            Ok(()) => Response::Ok(None),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Begin => match engine.begin() {
+            Ok(txn) => {
+                *transaction = Some(txn);
+                Response::Ok(None)
+            }
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Commit => match transaction.take().map(KvsTransaction::commit) {
+            Some(Ok(())) => Response::Ok(None),
+            Some(Err(KvError::Conflict)) => Response::Conflict,
+            Some(Err(e)) => Response::Err(e.to_string()),
+            None => Response::Err("No transaction in progress".to_owned()),
+        },
+        Request::Abort => match transaction.take() {
+            Some(_) => Response::Ok(None),
+            None => Response::Err("No transaction in progress".to_owned()),
+        },
+        Request::Export | Request::Watch { .. } => unreachable!("handled by the connection"),
+    }
+}
+
+/// Sends a `Pair` for every live pair of `engine`, and returns the
+/// response that ends the export.
+fn send_export<E: KvsEngine>(engine: &E, writer: &mut impl Write) -> Response {
+    let pairs = match engine.export_pairs() {
+        Ok(pairs) => pairs,
+        Err(e) => return Response::Err(e.to_string()),
+    };
+    for pair in pairs {
+        let sent = match pair {
+            Ok((key, value)) => serde_json::to_writer(&mut *writer, &Response::Pair { key, value }),
+            Err(e) => return Response::Err(e.to_string()),
+        };
+        if let Err(e) = sent {
+            return Response::Err(e.to_string());
+        }
+    }
+    Response::Ok(None)
+}
+
+/// Count of the watches a server is streaming, against its limit.
+struct Watchers {
+    count: AtomicUsize,
+    max: usize,
+}
+
+impl Watchers {
+    /// Creates a count that allows `max` watches.
+    fn new(max: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    /// Takes a slot for a new watch, or returns `None` if all are taken.
+    fn acquire(watchers: &Arc<Self>) -> Option<WatchSlot> {
+        watchers
+            .count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < watchers.max).then_some(count + 1)
+            })
+            .ok()?;
+        Some(WatchSlot(watchers.clone()))
+    }
+}
+
+/// A slot taken by a watch, given back when the watch ends.
+struct WatchSlot(Arc<Watchers>);
+
+impl Drop for WatchSlot {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Sends the events of `subscription` for keys starting with `prefix`
+/// until the client disconnects.
+///
+/// If the client reads too slowly and the engine drops the subscription,
+/// the client is told so with an `Err` before the connection is closed.
+fn stream_events(stream: &TcpStream, mut subscription: Subscription, prefix: &[u8]) -> Result<()> {
+    let mut writer = BufWriter::new(stream);
+    loop {
+        match subscription.next_timeout(WATCH_POLL_INTERVAL) {
+            Some(event) if event.key().starts_with(prefix) => {
+                let sent = serde_json::to_writer(&mut writer, &Response::Event(event))
+                    .map_err(io::Error::from)
+                    .and_then(|()| writer.flush());
+                if sent.is_err() {
+                    return Ok(());
+                }
+            }
+            Some(_) => {}
+            None if subscription.lagged() => {
+                let lagged = Response::Err("Watch dropped: too many unread events".to_owned());
+                serde_json::to_writer(&mut writer, &lagged)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            // Without events to write, a closed connection goes unnoticed
+            // unless we look.
+            None if is_closed(stream)? => return Ok(()),
+            None => {}
+        }
+    }
+}
+
+/// Whether the client has closed `stream`, without consuming any data it
+/// sent.
+fn is_closed(stream: &TcpStream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let closed = match stream.peek(&mut [0]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+    };
+    stream.set_nonblocking(false)?;
+    Ok(closed)
+}
+
+/// Whether `request` commits or aborts the open transaction.
+fn ends_transaction(request: &Request) -> bool {
+    matches!(request, Request::Commit | Request::Abort)
+}
+
+/// Handles a request made while a transaction is open.
+fn handle_in_transaction<T: KvsTransaction>(txn: &mut T, request: Request) -> Response {
+    match request {
+        Request::Set {
+            key,
+            value,
+            ttl_millis: None,
+        } => {
+            txn.set_bytes(key, value);
+            Response::Ok(None)
+        }
+        Request::Get { key } => match txn.get_bytes(key) {
+            Ok(value) => Response::Ok(value),
+            Err(e) => Response::Err(e.to_string()),
+        },
+        Request::Remove { key } => {
+            txn.remove_bytes(key);
+            Response::Ok(None)
+        }
+        Request::Set { .. } => Response::Err("TTL not supported in a transaction".to_owned()),
+        Request::GetVersioned { .. } => {
+            Response::Err("Versioned get not supported in a transaction".to_owned())
+        }
+        Request::Cas { .. } => Response::Err("CAS not supported in a transaction".to_owned()),
+        Request::WriteBatch(_) => Response::Err("Batch not supported in a transaction".to_owned()),
+        Request::Begin => Response::Err("Transaction already in progress".to_owned()),
+        Request::Export => Response::Err("Export not supported in a transaction".to_owned()),
+        Request::Watch { .. } => Response::Err("Watch not supported in a transaction".to_owned()),
+        Request::Commit | Request::Abort => unreachable!("handled outside the transaction"),
+    }
+}
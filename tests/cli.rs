@@ -1,4 +1,12 @@
+#![allow(
+    deprecated,
+    clippy::needless_borrows_for_generic_args,
+    clippy::zombie_processes
+)]
+
 use assert_cmd::prelude::*;
+use kvs::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -291,6 +299,63 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key3", "--new", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key3", "--new", "value5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Value mismatch"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key3",
+            "--expected",
+            "value4",
+            "--new",
+            "value5",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value5\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key4", "value6", "--ttl", "3600", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value6\n");
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -338,3 +403,341 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs-server backup` and `kvs-server restore` should carry a store over to
+// a new directory.
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let restore_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    // The running server holds the store.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("backup")
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("in use"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("backup")
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("restore")
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "sled", "restore"])
+        .arg(backup_dir.path())
+        .current_dir(&restore_dir)
+        .assert()
+        .failure();
+
+    let restore_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("restore")
+        .arg(backup_dir.path())
+        .current_dir(&restore_dir)
+        .assert()
+        .success();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&restore_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for (key, value) in [("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", addr])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(&data_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "kvs"])
+        .arg(&data_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "sled");
+    assert!(temp_dir.path().join("data.old").join("engine").exists());
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr])
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    child.kill().expect("server exited before killed");
+}
+
+// Keys with a TTL should keep their expiry time through a migration, and
+// keys that have already expired should not be copied.
+#[test]
+fn cli_migrate_keeps_expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let store = KvStore::open(&data_dir).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store
+        .set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+    store
+        .set_with_ttl(
+            "key3".to_owned(),
+            "value3".to_owned(),
+            Duration::from_millis(1),
+        )
+        .unwrap();
+    let expires_at = store.expiry("key2".to_owned()).unwrap();
+    assert!(expires_at.is_some());
+    drop(store);
+    fs::write(data_dir.join("engine"), "kvs").unwrap();
+    thread::sleep(Duration::from_millis(10));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+    let engine = SledKvsEngine::new(sled::open(&data_dir).unwrap());
+    assert_eq!(engine.expiry("key1".to_owned()).unwrap(), None);
+    assert_eq!(engine.expiry("key2".to_owned()).unwrap(), expires_at);
+    assert_eq!(engine.get("key3".to_owned()).unwrap(), None);
+    drop(engine);
+    // sled unlocks its directory from a background thread.
+    thread::sleep(Duration::from_millis(100));
+
+    fs::remove_dir_all(temp_dir.path().join("data.old")).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+    let store = KvStore::open(&data_dir).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.expiry("key1".to_owned()).unwrap(), None);
+    assert_eq!(store.expiry("key2".to_owned()).unwrap(), expires_at);
+    assert_eq!(store.get("key3".to_owned()).unwrap(), None);
+}
+
+// A migration interrupted between its two renames should be finished by
+// the next run.
+#[test]
+fn cli_migrate_interrupted() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let store = KvStore::open(&data_dir).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+    fs::write(data_dir.join("engine"), "kvs").unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+
+    // The state after a crash right after the first rename.
+    let staging = temp_dir.path().join("data.migrating");
+    fs::rename(&data_dir, &staging).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(&data_dir)
+        .assert()
+        .success();
+    assert!(!staging.exists());
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "sled");
+    let engine = SledKvsEngine::new(sled::open(&data_dir).unwrap());
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key2".to_owned(), "value3".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("1.log:").and(contains("total:")));
+
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log).unwrap();
+    let last = bytes.len() - 3;
+    bytes[last] ^= 0x01;
+    fs::write(&log, bytes).unwrap();
+    fs::write(temp_dir.path().join("notes.txt"), "").unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("corrupt record").and(contains("stray file")));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["verify", "--repair", "--force"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("verify")
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("corrupt").not());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// `kvs-admin verify` should read an encrypted store with the key from a key
+// file or from `KVS_ENCRYPTION_KEY`, and refuse to check it without one.
+#[test]
+fn cli_verify_encrypted() {
+    let temp_dir = TempDir::new().unwrap();
+    let data = temp_dir.path().join("data");
+    let key = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    let options = KvStoreOptions::new().encryption_key(key.parse::<EncryptionKey>().unwrap());
+    let store = KvStore::open_with(&data, options).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .env_remove("KVS_ENCRYPTION_KEY")
+        .arg("verify")
+        .arg(&data)
+        .assert()
+        .failure()
+        .stderr(contains("encryption key"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .env("KVS_ENCRYPTION_KEY", key)
+        .arg("verify")
+        .arg(&data)
+        .assert()
+        .success()
+        .stdout(contains("corrupt").not());
+
+    let key_file = temp_dir.path().join("key");
+    fs::write(&key_file, format!("{}\n", key)).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .env_remove("KVS_ENCRYPTION_KEY")
+        .arg("verify")
+        .arg(&data)
+        .arg("--encryption-key-file")
+        .arg(&key_file)
+        .assert()
+        .success();
+}
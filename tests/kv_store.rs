@@ -1,6 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CacheStats, Compression, Damage, EncryptionKey, Event, KvError, KvStore, KvStoreOptions,
+    KvsEngine, KvsSnapshot, KvsTransaction, RecoveryReport, Result, ScanIter, SledKvsEngine,
+    SyncPolicy, WriteBatch,
+};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +219,1959 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Flips one bit of the file at `offset` bytes from its end.
+fn flip_byte_from_end(path: &Path, offset: usize) {
+    let mut bytes = fs::read(path).expect("unable to read log file");
+    let idx = bytes.len() - offset;
+    bytes[idx] ^= 0x01;
+    fs::write(path, bytes).expect("unable to write log file");
+}
+
+// A flipped bit in a sealed log should be reported as corruption on open.
+#[test]
+fn corrupt_record_detected_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    flip_byte_from_end(&temp_dir.path().join("1.log"), 3);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption { gen: 1, pos }) => assert!(pos > 0),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    Ok(())
+}
+
+// A record failing its checksum in the newest log is a torn write, and
+// should be cut off on open along with everything after it.
+#[test]
+fn corrupt_record_in_newest_log_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let complete_len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    flip_byte_from_end(&temp_dir.path().join("1.log"), 3);
+
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.truncated_gen, Some(1));
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("1.log"))?.len(),
+        complete_len
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A record corrupted after the index was built should fail on read.
+#[test]
+fn corrupt_record_detected_on_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    flip_byte_from_end(&temp_dir.path().join("1.log"), 3);
+
+    match store.get("key1".to_owned()) {
+        Err(KvError::Corruption { gen: 1, pos: 0 }) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+// A partially written record at the end of the newest log should be
+// truncated on open, keeping every complete record before it.
+#[test]
+fn torn_tail_recovered_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Simulate a crash halfway through writing a third record.
+    let log = temp_dir.path().join("1.log");
+    let complete_len = fs::metadata(&log)?.len();
+    let mut bytes = fs::read(&log)?;
+    let first_record = bytes[..bytes.len() / 2].to_vec();
+    bytes.extend_from_slice(&first_record[..first_record.len() - 3]);
+    fs::write(&log, &bytes)?;
+
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(
+        report,
+        RecoveryReport {
+            generations_loaded: 1,
+            hints_loaded: 0,
+            truncated_gen: Some(1),
+            discarded_bytes: bytes.len() as u64 - complete_len,
+        }
+    );
+    assert_eq!(fs::metadata(&log)?.len(), complete_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.truncated_gen, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Recovery should be reported for a store opened with options, such as an
+// encrypted one.
+#[test]
+fn torn_tail_recovered_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::generate());
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let complete_len = fs::metadata(&log)?.len();
+    let mut bytes = fs::read(&log)?;
+    bytes.extend_from_within(..5);
+    fs::write(&log, &bytes)?;
+
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), options)?;
+    assert_eq!(report.truncated_gen, Some(1));
+    assert_eq!(report.discarded_bytes, 5);
+    assert_eq!(fs::metadata(&log)?.len(), complete_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A directory held by an open store should not be opened, restored into
+// or repaired until the store and its snapshots are gone.
+#[test]
+fn open_store_locks_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup_to(backup_dir.path())?;
+    let snapshot = store.snapshot()?;
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::StringError(_))
+    ));
+    assert!(matches!(
+        KvStore::repair(temp_dir.path(), false),
+        Err(KvError::StringError(_))
+    ));
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restored = KvStore::restore_from(restore_dir.path(), backup_dir.path())?;
+    assert!(matches!(
+        KvStore::open(restore_dir.path()),
+        Err(KvError::StringError(_))
+    ));
+    drop(restored);
+
+    drop(snapshot);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A log named other than `<gen>.log` is not part of the store, and should
+// be reported as a stray file rather than fail verification or open.
+#[test]
+fn misnamed_log_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let misnamed = temp_dir.path().join("007.log");
+    fs::copy(temp_dir.path().join("1.log"), &misnamed)?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_corrupt());
+    assert_eq!(report.generations.len(), 1);
+    assert!(report.duplicate_gens.is_empty());
+    assert_eq!(report.stray_files, vec![misnamed]);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A damaged length in a sealed log should be reported as corruption, not
+// cut off as a torn tail, since only the newest log can have one.
+#[test]
+fn damaged_length_not_mistaken_for_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log)?;
+    bytes[2] ^= 0x01;
+    fs::write(&log, &bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption { gen: 1, pos: 0 }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    assert_eq!(fs::metadata(&log)?.len(), bytes.len() as u64);
+    Ok(())
+}
+
+// A torn record whose partly written payload holds a valid-looking frame
+// should still be cut off whole on open.
+#[test]
+fn torn_tail_with_embedded_frame_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let complete_len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    let mut value = Vec::new();
+    value.extend_from_slice(&5u32.to_le_bytes());
+    value.extend_from_slice(&crc32fast::hash(b"value").to_le_bytes());
+    value.extend_from_slice(b"value");
+    value.extend_from_slice(b"padding");
+    store.set_bytes(b"key2".to_vec(), value)?;
+    drop(store);
+
+    // Simulate a crash before the end of the value was written.
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log)?;
+    bytes.truncate(bytes.len() - 3);
+    fs::write(&log, &bytes)?;
+
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.truncated_gen, Some(1));
+    assert_eq!(fs::metadata(&log)?.len(), complete_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_bytes(b"key2".to_vec())?, None);
+    Ok(())
+}
+
+// Verification should report damaged logs and leftover files without
+// failing, and repair should cut the damage so the store opens again.
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_corrupt());
+    assert_eq!(report.generations.len(), 2);
+    assert!(report.generations.iter().all(|g| g.damage.is_none()));
+    assert!(report.generations[0].stale_bytes() > 0);
+    assert_eq!(report.generations[1].stale_bytes(), 0);
+    assert!(report.live_bytes() < report.bytes());
+
+    let log1 = temp_dir.path().join("1.log");
+    let log2 = temp_dir.path().join("2.log");
+    flip_byte_from_end(&log1, 3);
+    let mut bytes = fs::read(&log2)?;
+    let complete_len = bytes.len() as u64;
+    bytes.extend_from_within(..5);
+    fs::write(&log2, &bytes)?;
+    fs::write(temp_dir.path().join("notes.txt"), "")?;
+    fs::write(temp_dir.path().join("7.hint"), "")?;
+    fs::copy(&log2, temp_dir.path().join("02.log"))?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_corrupt());
+    let corrupt_pos = match report.generations[0].damage {
+        Some(Damage::Corrupt { pos }) => pos,
+        other => panic!("unexpected damage: {:?}", other),
+    };
+    assert!(corrupt_pos > 0);
+    assert_eq!(
+        report.generations[1].damage,
+        Some(Damage::Truncated { pos: complete_len })
+    );
+    assert_eq!(report.duplicate_gens, vec![2]);
+    assert_eq!(report.orphaned_files, vec![temp_dir.path().join("7.hint")]);
+    assert_eq!(report.stray_files, vec![temp_dir.path().join("notes.txt")]);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::Corruption { gen: 1, .. })
+    ));
+
+    fs::remove_file(temp_dir.path().join("02.log"))?;
+    let log1_len = fs::metadata(&log1)?.len();
+    assert!(KvStore::repair(temp_dir.path(), false).is_err());
+    assert_eq!(fs::metadata(&log1)?.len(), log1_len);
+    assert_eq!(fs::metadata(&log2)?.len(), bytes.len() as u64);
+
+    // A backup may share a sealed log through a hard link.
+    let link_dir = TempDir::new().expect("unable to create temporary working directory");
+    let link = link_dir.path().join("1.log");
+    fs::hard_link(&log1, &link)?;
+    assert_eq!(
+        KvStore::repair(temp_dir.path(), true)?.generations,
+        report.generations
+    );
+    assert_eq!(fs::metadata(&log1)?.len(), corrupt_pos);
+    assert_eq!(fs::metadata(&log2)?.len(), complete_len);
+    assert_eq!(fs::metadata(&link)?.len(), log1_len);
+    assert!(!KvStore::verify(temp_dir.path())?.is_corrupt());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sync_policy_from_str() {
+    assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+    assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::Always);
+    assert_eq!(
+        "100ms".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::EveryNMillis(100)
+    );
+    assert_eq!(
+        "16writes".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::EveryNWrites(16)
+    );
+    assert!("0writes".parse::<SyncPolicy>().is_err());
+    assert!("0ms".parse::<SyncPolicy>().is_err());
+    assert!("sometimes".parse::<SyncPolicy>().is_err());
+}
+
+// Every sync policy should persist acknowledged writes across reopen.
+#[test]
+fn open_with_sync_policy() -> Result<()> {
+    for policy in [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::EveryNMillis(10),
+        SyncPolicy::EveryNWrites(3),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().sync_policy(policy);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// Concurrent durable writes committed in groups should all be persisted,
+// and concurrent removes of one key should succeed exactly once.
+#[test]
+fn concurrent_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("shared".to_owned(), "value".to_owned())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..16 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..20 {
+                store
+                    .set(format!("key{}_{}", thread_id, i), format!("value{}", i))
+                    .unwrap();
+            }
+            store.remove("shared".to_owned()).is_ok()
+        }));
+    }
+    let removed = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|&ok| ok)
+        .count();
+    assert_eq!(removed, 1);
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("shared".to_owned())?, None);
+    for thread_id in 0..16 {
+        for i in 0..20 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Writes until compaction has produced a hint file and returns its path.
+fn compact_until_hint(store: &KvStore, dir: &Path) -> Result<PathBuf> {
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        let hint = fs::read_dir(dir)?
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension() == Some("hint".as_ref()));
+        if let Some(hint) = hint {
+            return Ok(hint);
+        }
+    }
+    panic!("No compaction detected");
+}
+
+// Compacted generations should be indexed from their hint file, and a
+// damaged hint should fall back to replaying the log.
+#[test]
+fn hint_file_used_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let hint = compact_until_hint(&store, temp_dir.path())?;
+    let expected: Vec<_> = (0..1000)
+        .map(|key_id| store.get(format!("key{}", key_id)).unwrap())
+        .collect();
+    drop(store);
+
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.hints_loaded, 1);
+    for (key_id, value) in expected.iter().enumerate() {
+        assert_eq!(&store.get(format!("key{}", key_id))?, value);
+    }
+    drop(store);
+
+    flip_byte_from_end(&hint, 5);
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.hints_loaded, 0);
+    for (key_id, value) in expected.iter().enumerate() {
+        assert_eq!(&store.get(format!("key{}", key_id))?, value);
+    }
+
+    Ok(())
+}
+
+// Readers should keep making progress, and see correct values, while the
+// background thread compacts the log underneath them.
+#[test]
+fn reads_progress_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("stable{}", i), format!("value{}", i))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let done = done.clone();
+        let reads = reads.clone();
+        handles.push(thread::spawn(move || {
+            let mut i = thread_id;
+            while !done.load(Ordering::SeqCst) {
+                let key_id = i % 100;
+                assert_eq!(
+                    store.get(format!("stable{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+                reads.fetch_add(1, Ordering::SeqCst);
+                i += 1;
+            }
+        }));
+    }
+
+    // Overwrite a few hot keys until compaction has run several times,
+    // checking that reads advance between rounds.
+    let value = "x".repeat(1024);
+    let mut hint_gens = HashSet::new();
+    let mut rounds = 0;
+    while hint_gens.len() < 3 {
+        assert!(rounds < 10_000, "not enough compactions detected");
+        let reads_before = reads.load(Ordering::SeqCst);
+        for key_id in 0..100 {
+            store.set(format!("hot{}", key_id), value.clone())?;
+        }
+        while reads.load(Ordering::SeqCst) == reads_before {
+            thread::yield_now();
+        }
+        for entry in fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            if path.extension() == Some("hint".as_ref()) {
+                hint_gens.insert(path);
+            }
+        }
+        rounds += 1;
+    }
+
+    done.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("hot{}", key_id))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// Returns the number of files with the given extension in `dir`.
+fn count_files(dir: &Path, extension: &str) -> usize {
+    fs::read_dir(dir)
+        .expect("unable to read directory")
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some(extension.as_ref()))
+        .count()
+}
+
+// The active log should roll to a new generation at `max_file_size`.
+#[test]
+fn max_file_size_rolls_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_file_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..300 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(count_files(temp_dir.path(), "log") > 3);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        assert!(fs::metadata(path)?.len() < 1024 + 64);
+    }
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..300 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Readers should see every acknowledged write while the log they read from
+// is sealed under them, whether they read it before or after the roll.
+#[test]
+fn concurrent_reads_across_sealed_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_file_size(1024)
+        .compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let written = Arc::new(AtomicU64::new(0));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let written = written.clone();
+            thread::spawn(move || -> Result<()> {
+                while written.load(Ordering::Acquire) < 1000 {
+                    let n = written.load(Ordering::Acquire);
+                    for i in n.saturating_sub(20)..n {
+                        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+                    }
+                    // Old keys live in sealed generations by now.
+                    if n > 0 {
+                        let i = n / 2;
+                        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        written.store(i + 1, Ordering::Release);
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert!(count_files(temp_dir.path(), "log") > 10);
+    Ok(())
+}
+
+// A new store should write the compact binary records and say so in its
+// format marker.
+#[test]
+fn new_store_uses_binary_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert_eq!(fs::read_to_string(temp_dir.path().join("format"))?, "2");
+    // Header, version, kind, seq, expiry and length-prefixed key and value.
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("1.log"))?.len(),
+        8 + 4 + 5 + 7
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value1".to_owned(), 1))
+    );
+    Ok(())
+}
+
+// Writes `payloads` to `path` as framed log records.
+fn write_log(path: &Path, payloads: &[&str]) {
+    let mut bytes = Vec::new();
+    for payload in payloads {
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(payload.as_bytes()).to_le_bytes());
+        bytes.extend_from_slice(payload.as_bytes());
+    }
+    fs::write(path, bytes).expect("unable to write log file");
+}
+
+// A store written before the binary format should open and keep writing
+// JSON until compaction upgrades it.
+#[test]
+fn json_store_upgraded_on_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_log(
+        &temp_dir.path().join("1.log"),
+        &[
+            r#"{"Set":{"key":"key1","value":"value1","seq":1}}"#,
+            r#"{"Set":{"key":"key2","value":"value2","seq":2}}"#,
+            r#"{"Remove":{"key":"key2","seq":3}}"#,
+        ],
+    );
+    let options = KvStoreOptions::new().compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(!temp_dir.path().join("format").exists());
+    assert!(fs::read(temp_dir.path().join("2.log"))?
+        .windows(5)
+        .any(|w| w == b"{\"Set"));
+
+    overwrite_until_compacted(&store, temp_dir.path())?;
+    assert_eq!(fs::read_to_string(temp_dir.path().join("format"))?, "2");
+    let logs_hold_json = || -> Result<bool> {
+        for entry in fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            if path.extension() != Some("log".as_ref()) {
+                continue;
+            }
+            // Compaction may delete the log after it was listed.
+            let contents = match fs::read(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                contents => contents?,
+            };
+            if contents.windows(5).any(|w| w == b"{\"Set") {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while logs_hold_json()? {
+        assert!(Instant::now() < deadline, "JSON records were not rewritten");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value1".to_owned(), 1))
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Builds a large, repetitive value that compresses well.
+fn repetitive_value(tag: &str) -> String {
+    format!("{{\"{}\":[{}]}}", tag, "{\"field\":\"value\"},".repeat(200))
+}
+
+// Compressed records should shrink the log and stay readable when the
+// store is reopened with another codec or none.
+#[test]
+fn compressed_records_readable_with_any_codec() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let big = repetitive_value("lz4");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compression(Compression::Lz4),
+    )?;
+    store.set("big".to_owned(), big.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert_eq!(fs::read_to_string(temp_dir.path().join("format"))?, "3");
+    assert!(fs::metadata(temp_dir.path().join("1.log"))?.len() < big.len() as u64 / 4);
+
+    let zstd_big = repetitive_value("zstd");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().compression(Compression::Zstd),
+    )?;
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+    store.set("zstd".to_owned(), zstd_big.clone())?;
+    drop(store);
+    assert!(fs::metadata(temp_dir.path().join("2.log"))?.len() < zstd_big.len() as u64 / 4);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("zstd".to_owned())?, Some(zstd_big));
+    Ok(())
+}
+
+// Values below the size threshold should be written uncompressed.
+#[test]
+fn compression_min_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = repetitive_value("small");
+    let options = KvStoreOptions::new()
+        .compression(Compression::Lz4)
+        .compression_min_size(value.len() * 2);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), value.clone())?;
+    drop(store);
+    assert!(fs::metadata(temp_dir.path().join("1.log"))?.len() > value.len() as u64);
+    Ok(())
+}
+
+// Compaction should compress the records it copies with the configured
+// codec.
+#[test]
+fn compaction_compresses_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let big = repetitive_value("big");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("big".to_owned(), big.clone())?;
+    drop(store);
+
+    let options = KvStoreOptions::new()
+        .compaction_threshold(4 * 1024)
+        .compression(Compression::Zstd);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    overwrite_until_compacted(&store, temp_dir.path())?;
+    let needle = "{\"field\":\"value\"},".repeat(4);
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while files_contain(temp_dir.path(), needle.as_bytes())? {
+        assert!(
+            Instant::now() < deadline,
+            "uncompressed record was not rewritten"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+    Ok(())
+}
+
+// Whether any file in `dir` contains `needle`.
+fn files_contain(dir: &Path, needle: &[u8]) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // Compaction may delete the file after it was listed.
+        let contents = match fs::read(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            contents => contents?,
+        };
+        if contents.windows(needle.len()).any(|w| w == needle) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Neither logs nor hint files of an encrypted store should hold keys or
+// values in plaintext, and the store should not open without its key.
+#[test]
+fn encrypted_store_hides_plaintext() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::generate();
+    let options = KvStoreOptions::new()
+        .encryption_key(key.clone())
+        .compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned())?;
+    overwrite_until_compacted(&store, temp_dir.path())?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    drop(store);
+    assert!(count_files(temp_dir.path(), "hint") > 0);
+    assert!(!files_contain(temp_dir.path(), b"secret")?);
+    assert!(!files_contain(temp_dir.path(), b"value")?);
+    assert_eq!(fs::read_to_string(temp_dir.path().join("format"))?, "4");
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::StringError(_))
+    ));
+    assert!(KvStore::verify(temp_dir.path()).is_err());
+
+    let wrong_key = KvStoreOptions::new().encryption_key(EncryptionKey::generate());
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), wrong_key),
+        Err(KvError::Corruption { .. })
+    ));
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().encryption_key(key))?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    Ok(())
+}
+
+// A tampered ciphertext should fail to authenticate and be reported as
+// corruption.
+#[test]
+fn tampered_record_is_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::generate());
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // Flip a bit of the ciphertext and fix up the frame checksum, so that
+    // only the authentication tag can catch it.
+    let path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&path)?;
+    let last = log.len() - 1;
+    log[last] ^= 1;
+    let crc = crc32fast::hash(&log[8..]);
+    log[4..8].copy_from_slice(&crc.to_le_bytes());
+    fs::write(&path, log)?;
+
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), options.clone()),
+        Err(KvError::Corruption { gen: 1, pos: 0 })
+    ));
+    let report = KvStore::verify_with(temp_dir.path(), &options)?;
+    assert_eq!(
+        report.generations[0].damage,
+        Some(Damage::Corrupt { pos: 0 })
+    );
+    Ok(())
+}
+
+// Compaction should re-encrypt records sealed with a previous key, so that
+// the previous key can be dropped afterwards.
+#[test]
+fn encryption_key_rotated_on_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old_key = EncryptionKey::generate();
+    let new_key = EncryptionKey::generate();
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(old_key.clone()),
+    )?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let options = KvStoreOptions::new()
+        .encryption_key(new_key.clone())
+        .previous_encryption_key(old_key)
+        .compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    overwrite_until_compacted(&store, temp_dir.path())?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while temp_dir.path().join("1.log").exists() {
+        assert!(Instant::now() < deadline, "old generation was not removed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().encryption_key(new_key),
+    )?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Repeated reads should be served from the cache, and writes should never
+// leave a stale value behind.
+#[test]
+fn value_cache_hits_and_invalidation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().cache_size(1 << 20))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    // A clone shares the cache.
+    assert_eq!(
+        store.clone().get("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (2, 2));
+
+    // Without a budget nothing is cached or counted.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.get("key2".to_owned())?;
+    assert_eq!(store.cache_stats(), CacheStats::default());
+    Ok(())
+}
+
+// The cache should stay within its byte budget.
+#[test]
+fn value_cache_within_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let budget = 64 * 1024;
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().cache_size(budget))?;
+    let value = "x".repeat(1000);
+    for i in 0..500 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    for _ in 0..2 {
+        for i in 0..500 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+        }
+    }
+    let stats = store.cache_stats();
+    assert!(stats.bytes <= budget as u64);
+    assert!(stats.entries > 0 && stats.entries < 500);
+    assert_eq!(stats.hits + stats.misses, 1000);
+    Ok(())
+}
+
+// Values cached before a compaction should not be served for the copies
+// it writes, and the cached values of deleted generations are dropped.
+#[test]
+fn value_cache_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .cache_size(1 << 20)
+        .compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("other{}", i), format!("value{}", i))?;
+        store.get(format!("other{}", i))?;
+    }
+    assert_eq!(store.cache_stats().entries, 10);
+    overwrite_until_compacted(&store, temp_dir.path())?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    // Only the value of `key` read after the compaction may remain.
+    while store.cache_stats().entries > 1 {
+        assert!(Instant::now() < deadline, "cached values were not dropped");
+        thread::sleep(Duration::from_millis(10));
+    }
+    for i in 0..10 {
+        assert_eq!(
+            store.get(format!("other{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// Overwrites one key until a compaction has produced a hint file.
+fn overwrite_until_compacted(store: &KvStore, dir: &Path) -> Result<()> {
+    for i in 0..10_000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+        if count_files(dir, "hint") > 0 {
+            assert_eq!(store.get("key".to_owned())?, Some(format!("value{}", i)));
+            return Ok(());
+        }
+    }
+    panic!("No compaction detected");
+}
+
+// A small compaction threshold should trigger compaction early.
+#[test]
+fn custom_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    overwrite_until_compacted(&store, temp_dir.path())
+}
+
+// The stale-ratio trigger should compact even below the byte threshold.
+#[test]
+fn compaction_ratio_trigger() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(u64::MAX)
+        .compaction_ratio(0.9);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("other{}", i), "value".to_owned())?;
+    }
+    overwrite_until_compacted(&store, temp_dir.path())
+}
+
+// A stale ratio outside (0, 1] should be rejected on open.
+#[test]
+fn invalid_compaction_ratio() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for ratio in [0.0, -0.5, 1.5, f64::NAN] {
+        let options = KvStoreOptions::new().compaction_ratio(ratio);
+        assert!(matches!(
+            KvStore::open_with(temp_dir.path(), options),
+            Err(KvError::StringError(_))
+        ));
+    }
+    assert!(
+        KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compaction_ratio(1.0)).is_ok()
+    );
+}
+
+// A sync policy with a zero interval should be rejected on open.
+#[test]
+fn invalid_sync_interval() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for policy in [SyncPolicy::EveryNMillis(0), SyncPolicy::EveryNWrites(0)] {
+        let options = KvStoreOptions::new().sync_policy(policy);
+        assert!(matches!(
+            KvStore::open_with(temp_dir.path(), options),
+            Err(KvError::StringError(_))
+        ));
+    }
+}
+
+// Scans should return live pairs in key order for any engine.
+fn check_scan<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in ["b", "a2", "c", "a1", "a3", "d"] {
+        engine.set(key.to_owned(), format!("value-{}", key))?;
+    }
+    engine.remove("a3".to_owned())?;
+    engine.set("c".to_owned(), "value-c2".to_owned())?;
+
+    let collect = |iter: ScanIter| -> Result<Vec<(String, String)>> { iter.collect() };
+    let pair = |k: &str, v: &str| (k.to_owned(), v.to_owned());
+
+    assert_eq!(
+        collect(engine.scan(..)?)?,
+        vec![
+            pair("a1", "value-a1"),
+            pair("a2", "value-a2"),
+            pair("b", "value-b"),
+            pair("c", "value-c2"),
+            pair("d", "value-d"),
+        ]
+    );
+    assert_eq!(
+        collect(engine.scan("a2".to_owned().."c".to_owned())?)?,
+        vec![pair("a2", "value-a2"), pair("b", "value-b")]
+    );
+    assert_eq!(
+        collect(engine.scan("b".to_owned()..="c".to_owned())?)?,
+        vec![pair("b", "value-b"), pair("c", "value-c2")]
+    );
+    assert_eq!(
+        collect(engine.scan_prefix("a".to_owned())?)?,
+        vec![pair("a1", "value-a1"), pair("a2", "value-a2")]
+    );
+    assert!(collect(engine.scan_prefix("z".to_owned())?)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn scan_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(KvStore::open(temp_dir.path())?)?;
+
+    // The ordered index is rebuilt on reopen.
+    let store = KvStore::open(temp_dir.path())?;
+    let keys: Vec<String> = store
+        .scan(..)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, ["a1", "a2", "b", "c", "d"]);
+    Ok(())
+}
+
+#[test]
+fn scan_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// A batch should apply all of its writes, skipping removes of missing keys.
+fn check_write_batch<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned())
+        .remove("missing".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key3".to_owned())
+        .set("key4".to_owned(), "value4".to_owned());
+    assert_eq!(batch.len(), 6);
+    engine.write_batch(batch)?;
+    engine.write_batch(WriteBatch::new())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert_eq!(engine.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn write_batch_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn write_batch_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_write_batch(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn torn_write_batch_discarded_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let before_batch = fs::metadata(temp_dir.path().join("1.log"))?.len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Simulate a crash before the end of the batch reached the disk.
+    let log = temp_dir.path().join("2.log");
+    let len = fs::metadata(&log)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    let (store, report) = KvStore::open_with_report(temp_dir.path(), KvStoreOptions::new())?;
+    assert_eq!(report.truncated_gen, Some(2));
+    assert_eq!(report.discarded_bytes, len - 3);
+    assert_eq!(fs::metadata(&log)?.len(), 0);
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("1.log"))?.len(),
+        before_batch
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Compare-and-swap should only write when the current value matches.
+fn check_compare_and_swap<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = || "key1".to_owned();
+    let value = |v: &str| Some(v.to_owned());
+
+    assert!(!engine.compare_and_swap(key(), value("value0"), value("value1"))?);
+    assert!(engine.compare_and_swap(key(), None, value("value1"))?);
+    assert!(!engine.compare_and_swap(key(), None, value("value2"))?);
+    assert!(engine.compare_and_swap(key(), value("value1"), value("value2"))?);
+    assert_eq!(engine.get(key())?, value("value2"));
+    assert!(!engine.compare_and_swap(key(), value("value1"), None)?);
+    assert!(engine.compare_and_swap(key(), value("value2"), None)?);
+    assert_eq!(engine.get(key())?, None);
+    assert!(engine.compare_and_swap(key(), None, None)?);
+    assert_eq!(engine.get(key())?, None);
+
+    // Concurrent increments through compare-and-swap must not be lost.
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let engine = engine.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for _ in 0..25 {
+                loop {
+                    let current = engine.get("counter".to_owned())?;
+                    let next = current.as_deref().unwrap().parse::<u64>().unwrap() + 1;
+                    if engine.compare_and_swap(
+                        "counter".to_owned(),
+                        current,
+                        Some(next.to_string()),
+                    )? {
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter".to_owned())?, value("100"));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Keys and values that are not valid UTF-8 should be stored unchanged.
+fn check_binary_data<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = vec![0xff, 0x00, 0x01];
+    let value = vec![0x80, 0x81, 0x00, 0xfe];
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(vec![0xff, 0x02], b"text".to_vec())?;
+    engine.set("plain".to_owned(), "value".to_owned())?;
+
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(
+        engine.get_bytes(b"plain".to_vec())?,
+        Some(b"value".to_vec())
+    );
+    engine.set_bytes(b"k".to_vec(), vec![0xc3])?;
+    assert!(matches!(engine.get("k".to_owned()), Err(KvError::Utf8(_))));
+
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = engine
+        .scan_prefix_bytes(vec![0xff])?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![(key.clone(), value), (vec![0xff, 0x02], b"text".to_vec())]
+    );
+
+    assert!(engine.compare_and_swap_bytes(
+        key.clone(),
+        Some(vec![0x80, 0x81, 0x00, 0xfe]),
+        None
+    )?);
+    assert_eq!(engine.get_bytes(key)?, None);
+    Ok(())
+}
+
+#[test]
+fn binary_data_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary_data(KvStore::open(temp_dir.path())?)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(vec![0xff, 0x02])?, Some(b"text".to_vec()));
+    assert_eq!(store.get_bytes(b"k".to_vec())?, Some(vec![0xc3]));
+    Ok(())
+}
+
+#[test]
+fn binary_data_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_binary_data(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Keys set with a TTL should disappear once it has elapsed.
+fn check_ttl<E: KvsEngine>(engine: E) -> Result<()> {
+    let short = Duration::from_millis(200);
+    engine.set_with_ttl("session1".to_owned(), "token1".to_owned(), short)?;
+    engine.set_with_ttl("session2".to_owned(), "token2".to_owned(), short)?;
+    engine.set_with_ttl("session3".to_owned(), "token3".to_owned(), short)?;
+    engine.set_with_ttl(
+        "cache".to_owned(),
+        "entry".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl("plain".to_owned(), "old".to_owned(), short)?;
+    engine.set("plain".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        engine.get("session1".to_owned())?,
+        Some("token1".to_owned())
+    );
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get("session1".to_owned())?, None);
+    assert_eq!(engine.get("cache".to_owned())?, Some("entry".to_owned()));
+    assert_eq!(engine.get("plain".to_owned())?, Some("value".to_owned()));
+    let keys: Vec<String> = engine
+        .scan(..)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, ["cache", "plain"]);
+
+    assert!(matches!(
+        engine.remove("session1".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    assert!(!engine.compare_and_swap("session2".to_owned(), Some("token2".to_owned()), None)?);
+    assert!(engine.compare_and_swap("session2".to_owned(), None, Some("token4".to_owned()))?);
+    assert_eq!(
+        engine.get("session2".to_owned())?,
+        Some("token4".to_owned())
+    );
+
+    // Expiry times can be read back and written again as they are.
+    let expires_at = engine.expiry("cache".to_owned())?.expect("cache has a TTL");
+    assert!(expires_at > SystemTime::now() + Duration::from_secs(3500));
+    assert_eq!(engine.expiry("plain".to_owned())?, None);
+    assert_eq!(engine.expiry("missing".to_owned())?, None);
+    let mut batch = WriteBatch::new();
+    batch
+        .set_expiring_bytes(b"copy".to_vec(), b"entry".to_vec(), expires_at)
+        .set_expiring_bytes(
+            b"stale".to_vec(),
+            b"entry".to_vec(),
+            SystemTime::now() - Duration::from_secs(1),
+        );
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("copy".to_owned())?, Some("entry".to_owned()));
+    assert_eq!(engine.expiry("copy".to_owned())?, Some(expires_at));
+    assert_eq!(engine.get("stale".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn ttl_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(KvStore::open(temp_dir.path())?)?;
+
+    // Expiry times are persisted with the records.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session3".to_owned())?, None);
+    assert_eq!(store.get("session2".to_owned())?, Some("token4".to_owned()));
+    assert_eq!(store.get("cache".to_owned())?, Some("entry".to_owned()));
+    assert_eq!(
+        store.expiry("copy".to_owned())?,
+        store.expiry("cache".to_owned())?
+    );
+    Ok(())
+}
+
+#[test]
+fn ttl_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_ttl(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set_with_ttl(
+            format!("session{}", i),
+            "token".to_owned(),
+            Duration::from_millis(50),
+        )?;
+    }
+    store.set_with_ttl(
+        "cache".to_owned(),
+        "entry".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    overwrite_until_compacted(&store, temp_dir.path())?;
+
+    // Once the old generations are gone no log holds the expired records.
+    let logs_mention = |needle: &[u8]| -> Result<bool> {
+        for entry in fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            if path.extension() != Some("log".as_ref()) {
+                continue;
+            }
+            // Compaction may delete the log after it was listed.
+            let contents = match fs::read(&path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                contents => contents?,
+            };
+            if contents.windows(needle.len()).any(|w| w == needle) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while logs_mention(b"session")? {
+        assert!(
+            Instant::now() < deadline,
+            "expired records were not dropped"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(logs_mention(b"cache")?);
+    assert_eq!(store.get("cache".to_owned())?, Some("entry".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session0".to_owned())?, None);
+    assert_eq!(store.get("cache".to_owned())?, Some("entry".to_owned()));
+    Ok(())
+}
+
+// A snapshot should keep serving the data it was taken from while the
+// engine moves on.
+fn check_snapshot<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("a".to_owned(), "1".to_owned())?;
+    engine.set("b".to_owned(), "2".to_owned())?;
+    engine.set("c".to_owned(), "3".to_owned())?;
+
+    let snapshot = engine.snapshot()?;
+    engine.set("a".to_owned(), "10".to_owned())?;
+    engine.remove("b".to_owned())?;
+    engine.set("d".to_owned(), "4".to_owned())?;
+
+    assert_eq!(snapshot.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(snapshot.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(snapshot.get("d".to_owned())?, None);
+    let pairs: Vec<(String, String)> = snapshot.scan(..)?.collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        [("a", "1"), ("b", "2"), ("c", "3")].map(|(k, v)| (k.to_owned(), v.to_owned()))
+    );
+    let keys: Vec<String> = snapshot
+        .clone()
+        .scan_prefix("b".to_owned())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, ["b"]);
+
+    assert_eq!(engine.get("a".to_owned())?, Some("10".to_owned()));
+    assert_eq!(engine.get("b".to_owned())?, None);
+    assert_eq!(
+        engine.snapshot()?.get("d".to_owned())?,
+        Some("4".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn snapshot_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn snapshot_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_snapshot(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// Compaction should retire the generations a snapshot reads from rather
+// than delete them, until the snapshot is dropped.
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("pinned".to_owned(), "old".to_owned())?;
+    let snapshot = store.snapshot()?;
+    store.set("pinned".to_owned(), "new".to_owned())?;
+    overwrite_until_compacted(&store, temp_dir.path())?;
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while count_files(temp_dir.path(), "retired") == 0 {
+        assert!(Instant::now() < deadline, "no generation was retired");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(snapshot.get("pinned".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("pinned".to_owned())?, Some("new".to_owned()));
+
+    drop(snapshot);
+    assert_eq!(count_files(temp_dir.path(), "retired"), 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("pinned".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Transactions should read their own writes, apply them atomically on
+// commit and refuse to commit over a conflicting write.
+fn check_transaction<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("from".to_owned(), "100".to_owned())?;
+    engine.set("to".to_owned(), "0".to_owned())?;
+
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get("from".to_owned())?, Some("100".to_owned()));
+    txn.set("from".to_owned(), "60".to_owned());
+    txn.set("to".to_owned(), "40".to_owned());
+    txn.remove("missing".to_owned());
+    assert_eq!(txn.get("from".to_owned())?, Some("60".to_owned()));
+    assert_eq!(engine.get("from".to_owned())?, Some("100".to_owned()));
+    txn.commit()?;
+    assert_eq!(engine.get("from".to_owned())?, Some("60".to_owned()));
+    assert_eq!(engine.get("to".to_owned())?, Some("40".to_owned()));
+
+    // A key read by the transaction changes before it commits.
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get("from".to_owned())?, Some("60".to_owned()));
+    engine.set("from".to_owned(), "0".to_owned())?;
+    txn.set("to".to_owned(), "100".to_owned());
+    assert!(matches!(txn.commit(), Err(KvError::Conflict)));
+    assert_eq!(engine.get("to".to_owned())?, Some("40".to_owned()));
+
+    // A key read as absent is created before the transaction commits.
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get("lock".to_owned())?, None);
+    txn.set("lock".to_owned(), "mine".to_owned());
+    engine.set("lock".to_owned(), "theirs".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvError::Conflict)));
+    assert_eq!(engine.get("lock".to_owned())?, Some("theirs".to_owned()));
+
+    // Writes to keys the transaction did not read do not conflict.
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get("to".to_owned())?, Some("40".to_owned()));
+    engine.set("from".to_owned(), "1".to_owned())?;
+    txn.remove("lock".to_owned());
+    txn.commit()?;
+    assert_eq!(engine.get("lock".to_owned())?, None);
+
+    // Dropping a transaction discards its writes.
+    let mut txn = engine.begin()?;
+    txn.set("from".to_owned(), "999".to_owned());
+    drop(txn);
+    assert_eq!(engine.get("from".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn transaction_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(KvStore::open(temp_dir.path())?)?;
+
+    // Committed transactions are persisted like batches.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("to".to_owned())?, Some("40".to_owned()));
+    assert_eq!(store.get("lock".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn transaction_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_transaction(SledKvsEngine::new(sled::open(temp_dir.path())?))
+}
+
+// An overwrite with the same value should still conflict in a `KvStore`,
+// which validates by sequence number rather than by value.
+#[test]
+fn transaction_conflicts_on_rewrite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let mut txn = store.begin()?;
+    assert_eq!(txn.get("key".to_owned())?, Some("value".to_owned()));
+    store.set("key".to_owned(), "value".to_owned())?;
+    txn.set("other".to_owned(), "value".to_owned());
+    assert!(matches!(txn.commit(), Err(KvError::Conflict)));
+    Ok(())
+}
+
+// Every write should get a version greater than any handed out before.
+fn check_versioned<E: KvsEngine>(engine: E) -> Result<u64> {
+    assert_eq!(engine.get_versioned("key".to_owned())?, None);
+    engine.set("key".to_owned(), "value1".to_owned())?;
+    let (value, v1) = engine.get_versioned("key".to_owned())?.expect("key is set");
+    assert_eq!(value, "value1");
+
+    engine.set("other".to_owned(), "value".to_owned())?;
+    let (_, v2) = engine
+        .get_versioned("other".to_owned())?
+        .expect("key is set");
+    assert!(v2 > v1);
+    assert_eq!(
+        engine.get_versioned("key".to_owned())?.map(|(_, v)| v),
+        Some(v1)
+    );
+
+    engine.set("key".to_owned(), "value1".to_owned())?;
+    let (_, v3) = engine.get_versioned("key".to_owned())?.expect("key is set");
+    assert!(v3 > v2);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("a".to_owned(), "1".to_owned())
+        .set("b".to_owned(), "2".to_owned());
+    engine.write_batch(batch)?;
+    assert!(engine.compare_and_swap(
+        "key".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    let (_, v4) = engine.get_versioned("a".to_owned())?.expect("key is set");
+    let (_, v5) = engine.get_versioned("b".to_owned())?.expect("key is set");
+    let (value, v6) = engine.get_versioned("key".to_owned())?.expect("key is set");
+    assert_eq!(value, "value2");
+    assert!(v3 < v4 && v4 < v5 && v5 < v6);
+
+    engine.remove("key".to_owned())?;
+    assert_eq!(engine.get_versioned("key".to_owned())?, None);
+    Ok(v6)
+}
+
+#[test]
+fn versioned_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let last = check_versioned(KvStore::open(temp_dir.path())?)?;
+
+    // Versions are persisted and the counter resumes after them.
+    let store = KvStore::open(temp_dir.path())?;
+    let (_, version) = store.get_versioned("b".to_owned())?.expect("key is set");
+    assert!(version < last);
+    store.set("key".to_owned(), "value3".to_owned())?;
+    let (_, version) = store.get_versioned("key".to_owned())?.expect("key is set");
+    assert!(version > last);
+    Ok(())
+}
+
+#[test]
+fn versioned_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Reopening right after the first engine drops can race sled's file
+    // lock, so both engines share one database.
+    let db = sled::open(temp_dir.path())?;
+    let last = check_versioned(SledKvsEngine::new(db.clone()))?;
+
+    let engine = SledKvsEngine::new(db);
+    engine.set("key".to_owned(), "value3".to_owned())?;
+    let (_, version) = engine.get_versioned("key".to_owned())?.expect("key is set");
+    assert!(version > last);
+    Ok(())
+}
+
+// Versions of removed keys should not be handed out again once compaction
+// has deleted their records.
+#[test]
+fn versions_not_reused_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("temp{}", i), "value".to_owned())?;
+    }
+    store.set("trigger".to_owned(), "value".to_owned())?;
+    let (_, highest) = store
+        .get_versioned("trigger".to_owned())?
+        .expect("key is set");
+    for i in 0..100 {
+        store.remove(format!("temp{}", i))?;
+    }
+    drop(store);
+
+    // The next write crosses the threshold and compaction leaves no record
+    // of any key behind.
+    let options = KvStoreOptions::new().compaction_threshold(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.remove("trigger".to_owned())?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while fs::read_dir(temp_dir.path())?.any(|entry| {
+        entry.is_ok_and(|entry| {
+            let path = entry.path();
+            path.extension() == Some("log".as_ref())
+                && fs::read(&path).is_ok_and(|log| log.windows(4).any(|w| w == b"temp"))
+        })
+    }) {
+        assert!(Instant::now() < deadline, "records were not compacted");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("temp0".to_owned(), "value".to_owned())?;
+    let (_, version) = store
+        .get_versioned("temp0".to_owned())?
+        .expect("key is set");
+    assert!(version > highest);
+    Ok(())
+}
+
+// A subscription should report every write made after it, in order.
+fn check_subscribe<E: KvsEngine>(engine: E) -> Result<Vec<Event>> {
+    engine.set("before".to_owned(), "value".to_owned())?;
+    let mut subscription = engine.subscribe(u64::MAX)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key2".to_owned());
+    engine.write_batch(batch)?;
+
+    let events: Vec<Event> = (0..5)
+        .map(|_| {
+            subscription
+                .next_timeout(Duration::from_secs(5))
+                .expect("event not delivered")
+        })
+        .collect();
+    let mut writes: Vec<(&[u8], Option<&[u8]>)> = events
+        .iter()
+        .map(|event| match event {
+            Event::Set { key, value, .. } => (key.as_slice(), Some(value.as_slice())),
+            Event::Remove { key, .. } => (key.as_slice(), None),
+        })
+        .collect();
+    // Only writes to the same key are ordered within a batch.
+    writes[3..].sort();
+    assert_eq!(
+        writes,
+        vec![
+            (&b"key1"[..], Some(&b"value1"[..])),
+            (b"key2", Some(b"value2")),
+            (b"key1", None),
+            (b"key2", None),
+            (b"key3", Some(b"value3")),
+        ]
+    );
+    assert!(subscription
+        .next_timeout(Duration::from_millis(100))
+        .is_none());
+    Ok(events)
+}
+
+#[test]
+fn subscribe_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = check_subscribe(store.clone())?;
+    // The batch is reported in the order it was written.
+    assert_eq!(events[3].key(), b"key3");
+    assert!(events.windows(2).all(|w| w[0].seq() < w[1].seq()));
+    let (_, version) = store.get_versioned("key3".to_owned())?.expect("key is set");
+    assert_eq!(events[3].seq(), version);
+    Ok(())
+}
+
+#[test]
+fn subscribe_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_subscribe(SledKvsEngine::new(sled::open(temp_dir.path())?))?;
+    Ok(())
+}
+
+// A subscriber that stops reading should be dropped once its queue is
+// full, without holding up writers, and be able to tell why it ended.
+#[test]
+fn lagging_subscriber_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut subscription = store.subscribe(u64::MAX)?;
+    for i in 0..5000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(subscription.lagged());
+    let delivered = subscription.by_ref().count();
+    assert!(delivered > 0 && delivered < 5000);
+    assert!(!store.subscribe(u64::MAX)?.lagged());
+    Ok(())
+}
+
+// A subscription from a past sequence number should replay the writes
+// still in the log, followed by live ones.
+#[test]
+fn subscribe_replays_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (_, from) = store.get_versioned("key1".to_owned())?.expect("key is set");
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut subscription = store.subscribe(from)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let keys: Vec<(Vec<u8>, bool)> = (0..4)
+        .map(|_| {
+            let event = subscription
+                .next_timeout(Duration::from_secs(5))
+                .expect("event not delivered");
+            assert!(event.seq() >= from);
+            (event.key().to_vec(), matches!(event, Event::Set { .. }))
+        })
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            (b"key1".to_vec(), true),
+            (b"key2".to_vec(), true),
+            (b"key1".to_vec(), false),
+            (b"key3".to_vec(), true),
+        ]
+    );
+
+    // Without a live write there is nothing more to replay.
+    let mut subscription = store.subscribe(from + 100)?;
+    assert!(subscription
+        .next_timeout(Duration::from_millis(100))
+        .is_none());
+    Ok(())
+}
+
+// A backup taken while writes and compactions run should restore to the
+// state of the store at one point in the sequence of writes.
+#[test]
+fn backup_while_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(16 * 1024)
+        .max_file_size(8 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "0".to_owned())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..5000 {
+                store.set(format!("key{}", i % 50), i.to_string())?;
+            }
+            Ok(())
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    store.backup_to(backup_dir.path())?;
+    writer.join().unwrap()?;
+    assert!(store.backup_to(backup_dir.path()).is_err());
+
+    let restored = KvStore::restore_from(restore_dir.path(), backup_dir.path())?;
+    let values: Vec<(usize, u64)> = restored
+        .scan(..)?
+        .map(|pair| {
+            let (key, value) = pair?;
+            Ok((key[3..].parse().unwrap(), value.parse().unwrap()))
+        })
+        .collect::<Result<_>>()?;
+    let last = values.iter().map(|&(_, value)| value).max().unwrap();
+    assert_eq!(values.len() as u64, (last + 1).min(50));
+    for (key, value) in values {
+        assert_eq!(value, last - (last - key as u64) % 50);
+    }
+    drop(restored);
+    assert!(KvStore::restore_from(restore_dir.path(), backup_dir.path()).is_err());
+    Ok(())
+}
+
+// Restoring an encrypted backup should need its keys, and a failed
+// restore should leave the target directory empty.
+#[test]
+fn restore_encrypted_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key(EncryptionKey::generate());
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup_to(backup_dir.path())?;
+    drop(store);
+
+    assert!(KvStore::restore_from(restore_dir.path(), backup_dir.path()).is_err());
+    assert_eq!(fs::read_dir(restore_dir.path())?.count(), 0);
+    let wrong_key = KvStoreOptions::new().encryption_key(EncryptionKey::generate());
+    assert!(KvStore::restore_from_with(restore_dir.path(), backup_dir.path(), wrong_key).is_err());
+    assert_eq!(fs::read_dir(restore_dir.path())?.count(), 0);
+
+    let restored = KvStore::restore_from_with(restore_dir.path(), backup_dir.path(), options)?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A dump exported from one engine should import into the other and come
+// out the same.
+#[test]
+fn export_import_between_engines() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    for i in 0..2500 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    store.set_bytes(vec![0xff, 0x00], vec![0x80])?;
+    store.remove("key0001".to_owned())?;
+
+    let mut dump = Vec::new();
+    assert_eq!(store.export(&mut dump)?, 2500);
+    let text = String::from_utf8(dump.clone()).unwrap();
+    assert_eq!(
+        text.lines().next(),
+        Some(r#"{"key":"key0000","value":"value0"}"#)
+    );
+    assert_eq!(
+        text.lines().last(),
+        Some(r#"{"key":{"base64":"/wA="},"value":{"base64":"gA=="}}"#)
+    );
+
+    let engine = SledKvsEngine::new(sled::open(sled_dir.path())?);
+    engine.set("key0001".to_owned(), "kept".to_owned())?;
+    let with_blank_lines = text.replace('\n', "\n\n");
+    assert_eq!(engine.import(with_blank_lines.as_bytes())?, 2500);
+    assert_eq!(
+        engine.get("key2499".to_owned())?,
+        Some("value2499".to_owned())
+    );
+    assert_eq!(engine.get_bytes(vec![0xff, 0x00])?, Some(vec![0x80]));
+    assert_eq!(engine.get("key0001".to_owned())?, Some("kept".to_owned()));
+
+    engine.remove("key0001".to_owned())?;
+    let mut round_trip = Vec::new();
+    engine.export(&mut round_trip)?;
+    assert_eq!(round_trip, dump);
+
+    assert!(matches!(
+        store.import(&b"{\"key\":\"a\",\"value\":\"b\"}\nnot json\n"[..]),
+        Err(KvError::StringError(_))
+    ));
+    Ok(())
+}
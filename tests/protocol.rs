@@ -0,0 +1,225 @@
+use kvs::{
+    Event, KvError, KvStore, KvsClient, KvsServer, Request, Response, Result,
+    SharedQueueThreadPool, SledKvsEngine, ThreadPool,
+};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Text keys and values should keep their plain JSON string encoding, and
+// other bytes should survive a round trip.
+#[test]
+fn request_encoding() -> serde_json::Result<()> {
+    let text = Request::Set {
+        key: b"key".to_vec(),
+        value: b"value".to_vec(),
+        ttl_millis: None,
+    };
+    assert_eq!(
+        serde_json::to_string(&text)?,
+        r#"{"Set":{"key":"key","value":"value"}}"#
+    );
+
+    let binary = Request::Cas {
+        key: vec![0xff, 0xfe],
+        expected: None,
+        new: Some(vec![0x00, 0x80]),
+    };
+    let json = serde_json::to_string(&binary)?;
+    assert_eq!(
+        json,
+        r#"{"Cas":{"key":{"base64":"//4="},"expected":null,"new":{"base64":"AIA="}}}"#
+    );
+    match serde_json::from_str(&json)? {
+        Request::Cas { key, expected, new } => {
+            assert_eq!(key, vec![0xff, 0xfe]);
+            assert_eq!(expected, None);
+            assert_eq!(new, Some(vec![0x00, 0x80]));
+        }
+        request => panic!("unexpected request {:?}", request),
+    }
+
+    match serde_json::from_str(r#"{"Ok":{"base64":"gA=="}}"#)? {
+        Response::Ok(value) => assert_eq!(value, Some(vec![0x80])),
+        response => panic!("unexpected response {:?}", response),
+    }
+    Ok(())
+}
+
+#[test]
+fn client_binary_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+    let addr = "127.0.0.1:4010";
+    // The server runs until the test process exits.
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr)?;
+    let key = vec![0x00, 0xff, 0x10];
+    let value: Vec<u8> = (0..=255).collect();
+    client.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(client.get_bytes(key.clone())?, Some(value.clone()));
+    assert!(client.get("missing".to_owned())?.is_none());
+    assert!(client.compare_and_swap_bytes(key.clone(), Some(value), Some(vec![0x80]))?);
+    assert_eq!(client.get_bytes(key.clone())?, Some(vec![0x80]));
+    client.set_bytes(b"key".to_vec(), vec![0x80])?;
+    assert!(matches!(
+        client.get("key".to_owned()),
+        Err(KvError::Utf8(_))
+    ));
+    let (value, version) = client
+        .get_versioned_bytes(key.clone())?
+        .expect("key is set");
+    assert_eq!(value, vec![0x80]);
+    client.set_bytes(key.clone(), vec![0x81])?;
+    assert!(client
+        .get_versioned_bytes(key.clone())?
+        .is_some_and(|(_, v)| v > version));
+    client.remove_bytes(key.clone())?;
+    assert_eq!(client.get_bytes(key.clone())?, None);
+    assert_eq!(client.get_versioned_bytes(key)?, None);
+    Ok(())
+}
+
+// A transaction opened on a connection should buffer its writes until
+// commit and fail with a conflict if another client changes what it read.
+#[test]
+fn client_transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+    let addr = "127.0.0.1:4011";
+    // The server runs until the test process exits.
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+    client.set("balance".to_owned(), "100".to_owned())?;
+
+    client.begin()?;
+    assert_eq!(client.get("balance".to_owned())?, Some("100".to_owned()));
+    client.set("balance".to_owned(), "90".to_owned())?;
+    assert_eq!(client.get("balance".to_owned())?, Some("90".to_owned()));
+    assert_eq!(other.get("balance".to_owned())?, Some("100".to_owned()));
+    assert!(matches!(client.begin(), Err(KvError::StringError(_))));
+    client.commit()?;
+    assert_eq!(other.get("balance".to_owned())?, Some("90".to_owned()));
+
+    client.begin()?;
+    assert_eq!(client.get("balance".to_owned())?, Some("90".to_owned()));
+    other.set("balance".to_owned(), "50".to_owned())?;
+    client.set("balance".to_owned(), "80".to_owned())?;
+    assert!(matches!(client.commit(), Err(KvError::Conflict)));
+    assert_eq!(client.get("balance".to_owned())?, Some("50".to_owned()));
+
+    client.begin()?;
+    client.remove("balance".to_owned())?;
+    client.abort()?;
+    assert_eq!(client.get("balance".to_owned())?, Some("50".to_owned()));
+    assert!(matches!(client.abort(), Err(KvError::StringError(_))));
+    Ok(())
+}
+
+// A watching client should receive the writes to matching keys made by
+// other clients, without tying up a worker of the server's pool.
+#[test]
+fn client_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?);
+    let addr = "127.0.0.1:4012";
+    // The server runs until the test process exits.
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watch = KvsClient::connect(addr)?.watch(b"user/".to_vec())?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("user/1".to_owned(), "alice".to_owned())?;
+    client.set("order/1".to_owned(), "book".to_owned())?;
+    client.remove("user/1".to_owned())?;
+
+    match watch.next().expect("event not delivered")? {
+        Event::Set { key, value, .. } => {
+            assert_eq!(key, b"user/1");
+            assert_eq!(value, b"alice");
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    match watch.next().expect("event not delivered")? {
+        Event::Remove { key, .. } => assert_eq!(key, b"user/1"),
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    // `client` holds one of the pool's two workers and the watch none, so
+    // a third connection is served while both stay open.
+    let mut other = KvsClient::connect(addr)?;
+    assert_eq!(other.get("order/1".to_owned())?, Some("book".to_owned()));
+    drop(watch);
+    Ok(())
+}
+
+// Watches beyond the server's limit should be refused until a slot frees
+// up.
+#[test]
+fn client_watch_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?).max_watchers(1);
+    let addr = "127.0.0.1:4015";
+    // The server runs until the test process exits.
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let watch = KvsClient::connect(addr)?.watch(b"".to_vec())?;
+    assert!(matches!(
+        KvsClient::connect(addr)?.watch(b"".to_vec()),
+        Err(KvError::StringError(_))
+    ));
+
+    // The slot is given back once the server notices the client is gone.
+    drop(watch);
+    thread::sleep(Duration::from_secs(1));
+    KvsClient::connect(addr)?.watch(b"".to_vec())?;
+    Ok(())
+}
+
+// A dump exported through one server should import through another, even
+// one running a different engine.
+#[test]
+fn client_export_import() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_server = KvsServer::new(
+        KvStore::open(kvs_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let sled_engine = SledKvsEngine::new(sled::open(sled_dir.path())?);
+    let sled_server = KvsServer::new(sled_engine, SharedQueueThreadPool::new(2)?);
+    let (kvs_addr, sled_addr) = ("127.0.0.1:4013", "127.0.0.1:4014");
+    // The servers run until the test process exits.
+    thread::spawn(move || kvs_server.run(kvs_addr));
+    thread::spawn(move || sled_server.run(sled_addr));
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(kvs_addr)?;
+    for i in 0..100 {
+        client.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    client.set_bytes(vec![0xff], vec![0x00])?;
+    let mut dump = Vec::new();
+    assert_eq!(client.export(&mut dump)?, 101);
+    // The connection stays usable after an export.
+    assert_eq!(client.get("key42".to_owned())?, Some("value42".to_owned()));
+
+    let mut other = KvsClient::connect(sled_addr)?;
+    assert_eq!(other.import(dump.as_slice())?, 101);
+    assert_eq!(other.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(other.get_bytes(vec![0xff])?, Some(vec![0x00]));
+    let mut round_trip = Vec::new();
+    other.export(&mut round_trip)?;
+    assert_eq!(round_trip, dump);
+    Ok(())
+}